@@ -0,0 +1,81 @@
+use crossbeam_channel::Sender;
+use rumqtt::{MqttOptions, SecurityOptions};
+
+use crate::profile::{Profile, ReadyCheck};
+use crate::fairness::FairnessJob;
+use crate::verify::Verifier;
+
+/// How the users are driven on each shard.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    /// one thread ticks every user of the shard
+    Threads,
+    /// every user is a task on the shard's tokio runtime, with its own think timer
+    Async,
+}
+
+impl Default for Engine {
+    fn default() -> Self { Engine::Threads }
+}
+
+impl Engine {
+    pub fn parse(s: &str) -> Option<Engine> {
+        match s {
+            "threads" => Some(Engine::Threads),
+            "async" => Some(Engine::Async),
+            _ => None,
+        }
+    }
+}
+
+/// Run options handed from the command line to the event loop.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub report: Option<String>,
+    pub dashboard: bool,
+    /// bots are "1" to `users`
+    pub users: usize,
+    /// every random decision of the run derives from this
+    pub seed: u64,
+    pub verifier: Option<Verifier>,
+    /// matchmaking analyzer fed every start_game
+    pub fairness: Option<Sender<FairnessJob>>,
+    /// percent of the users acting out each misbehaving profile
+    pub profiles: Vec<(Profile, u32)>,
+    pub ready_check: ReadyCheck,
+    /// event loop threads, the users are split between them by id
+    pub shards: usize,
+    pub engine: Engine,
+    /// publisher connections, each user always publishes on the same one; 0 for the default
+    pub publishers: usize,
+    /// subscriber connections, at least one
+    pub subscribers: usize,
+    /// subscribers share "$share/" subscriptions instead of splitting the users between them
+    pub shared_subscriptions: bool,
+    /// threads decoding the responses, at least one
+    pub decoders: usize,
+}
+
+/// How to reach the MQTT broker, shared by every subcommand.
+#[derive(Clone, Debug)]
+pub struct Conn {
+    pub server: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// MQTT over websocket at this url instead of tcp to `server:port`
+    pub websocket: Option<String>,
+}
+
+impl Conn {
+    pub fn mqtt_options(&self, client_id: String) -> MqttOptions {
+        let mut mqtt_options = MqttOptions::new(client_id, self.server.as_str(), self.port);
+        mqtt_options = mqtt_options.set_keep_alive(100);
+        mqtt_options = mqtt_options.set_request_channel_capacity(10000);
+        if let Some(username) = &self.username {
+            let password = self.password.clone().unwrap_or_default();
+            mqtt_options = mqtt_options.set_security_opts(SecurityOptions::UsernamePassword(username.clone(), password));
+        }
+        mqtt_options
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::Instant;
+
+use crate::game::Games;
+use crate::stats;
+use crate::user::*;
+
+/// Redraws a plain ANSI terminal view of the run, driven from the event loop.
+pub struct Dashboard {
+    start: Instant,
+    last_at: Instant,
+    last_msgs: BTreeMap<(String, String), u64>,
+}
+
+pub fn state_key(u: &User) -> String {
+    let mut key = format!("{:?}", u.state);
+    if u.isRoomCreater { key.push_str("+creater"); }
+    if u.isChooseNGHero { key.push_str("+hero"); }
+    if u.isShed { key.push_str("+shed"); }
+    key
+}
+
+impl Dashboard {
+    pub fn new() -> Dashboard {
+        Dashboard {
+            start: Instant::now(),
+            last_at: Instant::now(),
+            last_msgs: BTreeMap::new(),
+        }
+    }
+
+    /// `states` counts the users by `state_key`, over all shards.
+    pub fn draw(&mut self, states: &BTreeMap<String, usize>, users: usize, rooms: usize, games: &Games) {
+        let msgs = stats::messages();
+        let secs = self.last_at.elapsed().as_secs_f64().max(0.001);
+
+        let mut o = String::new();
+        o.push_str("\x1b[2J\x1b[H");
+        o.push_str(&format!("erps-test  {}s  users: {}  rooms: {}  games: {}  publish queue: {}\n\n",
+            self.start.elapsed().as_secs(), users, rooms, games.live(),
+            stats::get(&stats::PUBLISH_QUEUE_DEPTH)));
+        o.push_str("users by state\n");
+        for (k, n) in states {
+            o.push_str(&format!("  {:<48} {:>6}\n", k, n));
+        }
+        o.push_str("\nmessages/sec\n");
+        for ((dir, topic), n) in &msgs {
+            let prev = self.last_msgs.get(&(dir.clone(), topic.clone())).cloned().unwrap_or(0);
+            o.push_str(&format!("  {:<4} {:<40} {:>8.1}\n", dir, topic, (n - prev) as f64 / secs));
+        }
+        o.push_str(&format!("\npublished: {}  failed: {}  dropped: {}  shed: {}\n",
+            stats::get(&stats::PUBLISHED), stats::get(&stats::PUBLISH_FAILED),
+            stats::get(&stats::PUBLISH_DROPPED), stats::get(&stats::USERS_SHED)));
+        o.push_str("\nrecent errors\n");
+        for e in stats::recent_errors() {
+            o.push_str(&format!("  {}\n", e));
+        }
+        let out = io::stdout();
+        let mut out = out.lock();
+        let _ = out.write_all(o.as_bytes());
+        let _ = out.flush();
+
+        self.last_msgs = msgs;
+        self.last_at = Instant::now();
+    }
+}
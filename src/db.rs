@@ -0,0 +1,61 @@
+use log::info;
+use failure::Error;
+use mysql;
+
+// The ERPS tables the tool reads and writes. Keep these in step with the server's schema.
+pub const SQL_RESULT: &str = "SELECT id, win FROM game_result WHERE game=?";
+pub const SQL_INFO: &str = "SELECT id, hero, level, damage, take_damage, heal, kills, death, assist FROM game_info WHERE game=?";
+pub const SQL_RATING: &str = "SELECT score FROM user_rk WHERE id=?";
+
+pub const SQL_ADD_USER: &str = "INSERT INTO user (id, name, status) VALUES (?, ?, 'offline') ON DUPLICATE KEY UPDATE status='offline'";
+pub const SQL_ADD_HERO: &str = "INSERT IGNORE INTO user_hero (id, hero) VALUES (?, ?)";
+pub const SQL_ADD_RATING: &str = "INSERT INTO user_rk (id, score) VALUES (?, ?) ON DUPLICATE KEY UPDATE score=VALUES(score)";
+pub const SQL_DEL_RATING: &str = "DELETE FROM user_rk WHERE id=?";
+pub const SQL_DEL_HEROES: &str = "DELETE FROM user_hero WHERE id=?";
+pub const SQL_DEL_USER: &str = "DELETE FROM user WHERE id=?";
+
+pub const TEST_HEROES: &[&str] = &["hero_1", "hero_2", "hero_3"];
+pub const INITIAL_RATING: i32 = 1000;
+
+pub fn connect(url: &str) -> Result<mysql::Pool, Error> {
+    let opts = mysql::Opts::from_url(url)?;
+    // not the url, it has the password in it
+    let at = format!("{}:{}/{}", opts.get_ip_or_hostname().unwrap_or(""), opts.get_tcp_port(), opts.get_db_name().unwrap_or(""));
+    let pool = mysql::Pool::new(opts)?;
+    info!("connected to mysql {}", at);
+    Ok(pool)
+}
+
+/// Create the accounts the bots log in as, "1" to `users`.
+pub fn setup_users(pool: &mysql::Pool, users: usize) -> Result<(), Error> {
+    let mut t = pool.start_transaction(false, None, None)?;
+    for i in 1..users + 1 {
+        let id = i.to_string();
+        t.prep_exec(SQL_ADD_USER, (&id, format!("erps_test_{}", id)))?;
+        for hero in TEST_HEROES {
+            t.prep_exec(SQL_ADD_HERO, (&id, hero))?;
+        }
+        t.prep_exec(SQL_ADD_RATING, (&id, INITIAL_RATING))?;
+    }
+    t.commit()?;
+    info!("created {} test users", users);
+    Ok(())
+}
+
+pub fn teardown_users(pool: &mysql::Pool, users: usize) -> Result<(), Error> {
+    let mut t = pool.start_transaction(false, None, None)?;
+    for i in 1..users + 1 {
+        let id = i.to_string();
+        t.prep_exec(SQL_DEL_RATING, (&id,))?;
+        t.prep_exec(SQL_DEL_HEROES, (&id,))?;
+        t.prep_exec(SQL_DEL_USER, (&id,))?;
+    }
+    t.commit()?;
+    info!("removed {} test users", users);
+    Ok(())
+}
+
+pub fn rating(pool: &mysql::Pool, id: &str) -> Result<Option<i32>, Error> {
+    let row = pool.first_exec(SQL_RATING, (id,))?;
+    Ok(row.map(|r| mysql::from_row::<i32>(r)))
+}
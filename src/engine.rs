@@ -0,0 +1,801 @@
+use log::{info, warn};
+use serde_json::json;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{bounded, unbounded, tick, Sender, Receiver, select};
+use indexmap::IndexMap;
+use rand::Rng;
+
+use crate::config::{Config, Engine};
+use crate::dashboard::{self, Dashboard};
+use crate::event::*;
+use crate::fairness::{FairnessJob, Seat};
+use crate::game::{self, Games};
+use crate::msg::*;
+use crate::profile;
+use crate::record;
+use crate::rng;
+use crate::stats;
+use crate::store::UserStore;
+use crate::tasks;
+use crate::user::*;
+
+/// Shards when the run does not ask for a number. Fixed rather than one per core, rooms are only
+/// joined on their own shard so the same seed has to see the same split on every machine.
+pub const SHARDS: usize = 4;
+
+/// What a shard gets from the router: events for its users and rooms.
+pub(crate) enum ShardMsg {
+    Event(UserEvent),
+    Shutdown,
+}
+
+/// What a shard gets from the coordinator: start_game listed these users of the shard.
+pub(crate) struct GameStarted {
+    pub game: u32,
+    pub ids: Vec<String>,
+}
+
+/// What the coordinator gets: game events, and what the shards report back.
+pub(crate) enum CoordMsg {
+    Event(UserEvent),
+    StartGet(String),
+    /// the shard finished its players of `game`
+    Seats { game: u32, seats: Vec<PlayerSeat> },
+    Snapshot { shard: usize, snap: Snapshot },
+    /// users playing on a shard, for the gameless check
+    Playing { shard: usize, ids: BTreeSet<String> },
+}
+
+pub(crate) struct PlayerSeat {
+    id: String,
+    hero: String,
+    /// room the player queued with, its own id when it had none
+    party: String,
+}
+
+/// A shard's gauges, sent every tick and summed by the coordinator.
+#[derive(Default)]
+pub(crate) struct Snapshot {
+    logged_in: usize,
+    in_room: usize,
+    queued: usize,
+    playing: usize,
+    rooms: (usize, usize, usize),
+    /// dashboard state key -> users, only filled for the dashboard
+    states: BTreeMap<String, usize>,
+}
+
+/// A start_game waiting for the member shards to report their players.
+struct PendingGame {
+    res: StartGameRes,
+    shards: usize,
+    seats: BTreeMap<String, PlayerSeat>,
+}
+
+/// Which shard owns bot `id`, and rooms created by it. None when there is no such bot out of `users`.
+fn shard_of(id: &str, users: usize, shards: usize) -> Option<usize> {
+    id.parse::<usize>().ok().filter(|n| *n >= 1 && *n <= users).map(|n| (n - 1) % shards)
+}
+
+/// Start the shards, the coordinator and the router feeding them, returns the router's sender.
+pub fn start(msgtx: Sender<MqttMsg>, cfg: Config, stop: Receiver<()>) -> Sender<UserEvent> {
+    let shards = cfg.shards.max(1);
+    let users = cfg.users;
+    info!("{:?} event engine running on {} shards", cfg.engine, shards);
+    let (tx, rx): (Sender<UserEvent>, Receiver<UserEvent>) = bounded(10000);
+    let (coord_tx, coord_rx) = bounded(10000);
+    let mut shard_txs = Vec::new();
+    // the coordinator's own, unbounded so it never waits on a shard that is waiting on it
+    let mut game_txs = Vec::new();
+    for n in 0..shards {
+        let (stx, srx) = bounded(10000);
+        let (gtx, grx) = unbounded();
+        shard_txs.push(stx);
+        game_txs.push(gtx);
+        let (msgtx, cfg, coord_tx, stop) = (msgtx.clone(), cfg.clone(), coord_tx.clone(), stop.clone());
+        match cfg.engine {
+            Engine::Threads => thread::spawn(move || run_shard(n, shards, msgtx, cfg, srx, grx, coord_tx, stop)),
+            Engine::Async => thread::spawn(move || tasks::run_shard(n, shards, msgtx, cfg, srx, grx, coord_tx, stop)),
+        };
+    }
+    {
+        let stop = stop.clone();
+        thread::spawn(move || run_coordinator(msgtx, cfg, coord_rx, game_txs, stop));
+    }
+    thread::spawn(move || route(rx, shard_txs, coord_tx, users, stop));
+    tx
+}
+
+/// Hand every event to the shard of the user or room it is about.
+fn route(rx: Receiver<UserEvent>, shards: Vec<Sender<ShardMsg>>, coord: Sender<CoordMsg>, users: usize, stop: Receiver<()>) {
+    loop {
+        select! {
+            recv(stop) -> _ => break,
+            recv(rx) -> ev => {
+                let ev = match ev {
+                    Ok(ev) => ev,
+                    Err(_) => break,
+                };
+                let key = match &ev {
+                    UserEvent::Shutdown(_) => {
+                        for s in &shards {
+                            let _ = s.send(ShardMsg::Shutdown);
+                        }
+                        let _ = coord.send(CoordMsg::Event(ev));
+                        continue;
+                    }
+                    UserEvent::Start(_) | UserEvent::GameSingal(_) | UserEvent::StartGame(_) | UserEvent::GameOver(_) => {
+                        let _ = coord.send(CoordMsg::Event(ev));
+                        continue;
+                    }
+                    ev => user_key(ev).unwrap_or("").to_owned(),
+                };
+                match shard_of(&key, users, shards.len()) {
+                    Some(n) => { let _ = shards[n].send(ShardMsg::Event(ev)); }
+                    None => {
+                        stats::inc(&stats::HANDLER_ERRORS);
+                        stats::record_error(format!("response for unknown user {:?}", key));
+                        warn!("no user {:?} to hand the response to", key);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The user, or the room's creater, an event is about. None for game wide events.
+pub(crate) fn user_key(ev: &UserEvent) -> Option<&str> {
+    match ev {
+        UserEvent::Login(x) => Some(&x.id),
+        UserEvent::Logout(x) => Some(&x.id),
+        UserEvent::Create(x) => Some(&x.id),
+        UserEvent::Close(x) => Some(&x.room),
+        UserEvent::ChooseNGHero(x) => Some(&x.id),
+        UserEvent::Invite(x) => Some(&x.id),
+        UserEvent::StartQueue(x) => Some(&x.id),
+        UserEvent::CancelQueue(x) => Some(&x.room),
+        UserEvent::PreStart(x) => Some(&x.id),
+        UserEvent::Join(x) => Some(&x.id),
+        UserEvent::StartGet(x) => Some(&x.id),
+        UserEvent::Ready(x) => Some(&x.room),
+        UserEvent::Dead(x) => Some(&x.id),
+        UserEvent::Start(_) | UserEvent::GameSingal(_) | UserEvent::StartGame(_) | UserEvent::GameOver(_) | UserEvent::Shutdown(_) => None,
+    }
+}
+
+pub(crate) fn set_room_state(rooms: &IndexMap<String, Rc<RefCell<RoomRecord>>>, room: &str, state: RoomState) {
+    if let Some(r) = rooms.get(room) {
+        r.borrow_mut().state = state;
+    }
+}
+
+/// Drop closed rooms, members who left and rooms whose creater is gone, returns the open, queued
+/// and in game room counts.
+pub(crate) fn clean_rooms(rooms: &mut IndexMap<String, Rc<RefCell<RoomRecord>>>, users: &UserStore) -> (usize, usize, usize) {
+    let in_room = |id: &String, room: &String| users.get(id).map(|u| u.room == *room).unwrap_or(false);
+    for (id, r) in rooms.iter() {
+        let mut r = r.borrow_mut();
+        if r.state != RoomState::Closed && !in_room(id, id) {
+            // the creater logged out or went afk, the server drops the room with them
+            info!("room {} lost its creater", id);
+            r.state = RoomState::Closed;
+        }
+        r.ids.retain(|m| in_room(m, id));
+    }
+    rooms.retain(|_, r| r.borrow().state != RoomState::Closed);
+    let (mut open, mut queued, mut in_game) = (0, 0, 0);
+    for (_, r) in rooms.iter() {
+        match r.borrow().state {
+            RoomState::Open => open += 1,
+            RoomState::Queued => queued += 1,
+            RoomState::InGame => in_game += 1,
+            RoomState::Closed => {}
+        }
+    }
+    (open, queued, in_game)
+}
+
+pub(crate) fn snapshot(users: &UserStore, rooms: (usize, usize, usize), states: bool) -> Snapshot {
+    let mut s = Snapshot { rooms: rooms, ..Default::default() };
+    for u in users.iter() {
+        if states {
+            *s.states.entry(dashboard::state_key(u)).or_insert(0) += 1;
+        }
+        match u.state {
+            UserState::Offline => continue,
+            UserState::InRoom => s.in_room += 1,
+            UserState::Queued | UserState::ReadyCheck | UserState::Ready => s.queued += 1,
+            UserState::Playing => s.playing += 1,
+            _ => {}
+        }
+        s.logged_in += 1;
+    }
+    s
+}
+
+/// The bots a shard owns, "n + 1", "n + 1 + shards", ...
+pub(crate) fn shard_users(n: usize, shards: usize, cfg: &Config) -> UserStore {
+    UserStore::sharded((1..cfg.users + 1).filter(|i| (i - 1) % shards == n).map(|i| {
+        let mut user_rng = rng::user_rng(cfg.seed, &i.to_string());
+        let profile = profile::pick(&cfg.profiles, &mut user_rng);
+        User {
+            id: i.to_string(),
+            hero: "".to_string(),
+            cnt: -1,
+            rng: user_rng,
+            profile: profile,
+            ready_check: cfg.ready_check,
+            ..Default::default()
+        }
+    }).collect(), n, shards)
+}
+
+/// What a user does on its tick.
+pub(crate) fn act(u: &mut User, shutdown: bool, tx: &mut Sender<MqttMsg>, rooms: &mut IndexMap<String, Rc<RefCell<RoomRecord>>>) {
+    u.check_ready();
+    if shutdown {
+        u.back_action(tx);
+    } else {
+        u.next_action(tx, rooms);
+    }
+}
+
+/// Finish the shard's players of a started game, the coordinator makes up the result from the seats.
+pub(crate) fn seat_players(TotalUsers: &mut UserStore, rooms: &IndexMap<String, Rc<RefCell<RoomRecord>>>, ids: Vec<String>) -> Vec<PlayerSeat> {
+    let mut seats = Vec::new();
+    for id in ids {
+        let seat = TotalUsers.update_id(&id, |u| {
+            // rooms before game_over clears them
+            let party = if u.room.is_empty() { u.id.clone() } else { u.room.clone() };
+            // players who abandoned are already offline
+            if u.state == UserState::Playing {
+                u.game_started();
+                u.game_over();
+            }
+            PlayerSeat { id: u.id.clone(), hero: u.hero.clone(), party: party }
+        });
+        if let Some(seat) = seat {
+            // the server closes the rooms that went into the game
+            set_room_state(rooms, &seat.party, RoomState::Closed);
+            seats.push(seat);
+        }
+    }
+    seats
+}
+
+/// One shard: bots "n + 1", "n + 1 + shards", ... and the rooms they create. Bots only join
+/// rooms of their own shard, there are no joins across shards, so a room's members never span
+/// shards and the parties of a run depend on the shard count, which the report keeps. With the
+/// bots' team size of 1 no room takes a second member anyway; a bigger one needs those joins
+/// before parties mix bots of different shards.
+///
+/// `rx` brings the router's events and `games` the coordinator's started games.
+fn run_shard(n: usize, shards: usize, mut tx: Sender<MqttMsg>, cfg: Config, rx: Receiver<ShardMsg>,
+             games: Receiver<GameStarted>, coord: Sender<CoordMsg>, stop: Receiver<()>) {
+    let update500ms = tick(Duration::from_millis(500));
+    let update1s = tick(Duration::from_secs(1));
+    let update10s = tick(game::PLAYING_EVERY);
+    let mut rooms: IndexMap<String, Rc<RefCell<RoomRecord>>> = IndexMap::new();
+    let mut TotalUsers = shard_users(n, shards, &cfg);
+    let mut room_counts = (0, 0, 0);
+    let mut shutdown = false;
+    loop {
+        select! {
+            recv(stop) -> _ => break,
+            recv(update500ms) -> _ => {
+                for i in 0..TotalUsers.len() {
+                    TotalUsers.update(i, |u| act(u, shutdown, &mut tx, &mut rooms));
+                }
+                let _ = coord.try_send(CoordMsg::Snapshot { shard: n, snap: snapshot(&TotalUsers, room_counts, cfg.dashboard) });
+            }
+            recv(update1s) -> _ => {
+                room_counts = clean_rooms(&mut rooms, &TotalUsers);
+            }
+            recv(update10s) -> _ => {
+                let ids = TotalUsers.iter().filter(|u| u.state == UserState::Playing).map(|u| u.id.clone()).collect();
+                let _ = coord.send(CoordMsg::Playing { shard: n, ids: ids });
+            }
+            recv(rx) -> m => {
+                match m {
+                    Ok(ShardMsg::Event(ev)) => handle(ev, &mut TotalUsers, &mut rooms, &mut tx, &coord),
+                    Ok(ShardMsg::Shutdown) => {
+                        info!("shard {} walking {} users back offline", n, TotalUsers.len());
+                        shutdown = true;
+                    }
+                    Err(_) => break,
+                }
+            }
+            recv(games) -> m => {
+                if let Ok(GameStarted { game, ids }) = m {
+                    // the members' start_get was queued before the game reached the coordinator
+                    for m in rx.try_iter() {
+                        match m {
+                            ShardMsg::Event(ev) => handle(ev, &mut TotalUsers, &mut rooms, &mut tx, &coord),
+                            ShardMsg::Shutdown => shutdown = true,
+                        }
+                    }
+                    let seats = seat_players(&mut TotalUsers, &rooms, ids);
+                    let _ = coord.send(CoordMsg::Seats { game: game, seats: seats });
+                }
+            }
+        }
+    }
+}
+
+/// Everything about one user or room, on the shard that owns it.
+pub(crate) fn handle(ev: UserEvent, TotalUsers: &mut UserStore, rooms: &mut IndexMap<String, Rc<RefCell<RoomRecord>>>,
+          tx: &mut Sender<MqttMsg>, coord: &Sender<CoordMsg>) {
+    match ev {
+        UserEvent::Join(x) => {
+            if x.msg == "ok" {
+                TotalUsers.update_id(&x.id, |u| {
+                    u.cnt = -1;
+                    u.got_res("join");
+                    u.get_join(x.room.clone());
+                });
+                let r = rooms.get(&x.room);
+                if let Some(r) = r {
+                    if !r.borrow().ids.contains(&x.id) {
+                        r.borrow_mut().ids.push(x.id.clone());
+                    }
+                }
+            } else if let Some(r) = rooms.get(&x.room) {
+                // the server no longer takes members for a room we still think is open
+                if r.borrow().state == RoomState::Open {
+                    warn!("join {} refused, dropping the room", x.room);
+                    stats::inc(&stats::ROOMS_STALE);
+                    stats::record_error(format!("join {} refused for an open room", x.room));
+                    r.borrow_mut().state = RoomState::Closed;
+                }
+            }
+        },
+        UserEvent::Login(x) => {
+            TotalUsers.update_id(&x.id, |u| {
+                u.cnt = -1;
+                u.got_res("login");
+                u.get_login();
+            });
+        },
+        UserEvent::Logout(x) => {
+            if TotalUsers.get(&x.id).is_some() {
+                // the creater takes the room with them
+                set_room_state(rooms, &x.id, RoomState::Closed);
+            }
+            TotalUsers.update_id(&x.id, |u| {
+                u.cnt = -1;
+                u.got_res("logout");
+                u.get_logout();
+            });
+        },
+        UserEvent::Create(x) => {
+            if x.msg == "ok" {
+                TotalUsers.update_id(&x.id, |u| {
+                    u.cnt = -1;
+                    u.got_res("create");
+                    u.get_create();
+                });
+                rooms.insert(x.id.clone(), Rc::new(RefCell::new(
+                    RoomRecord { id: x.id.clone(), ids: vec![x.id.clone()], state: RoomState::Open })));
+            }
+        },
+        UserEvent::Close(x) => {
+            TotalUsers.update_id(&x.room, |u| u.got_res("close"));
+            // the members leave with the room
+            for i in TotalUsers.members(&x.room) {
+                TotalUsers.update(i, |u| {
+                    u.cnt = -1;
+                    u.get_close();
+                });
+            }
+            set_room_state(rooms, &x.room, RoomState::Closed);
+        },
+        UserEvent::ChooseNGHero(x) => {
+            TotalUsers.update_id(&x.id, |u| {
+                u.cnt = -1;
+                u.got_res("choose_hero");
+                u.get_choose_hero(x.hero.clone());
+            });
+        },
+        UserEvent::Invite(x) => {
+            TotalUsers.update_id(&x.id, |u| {
+                u.cnt = -1;
+                u.get_invite();
+            });
+        },
+        UserEvent::StartQueue(x) => {
+            TotalUsers.update_id(&x.id, |u| {
+                u.cnt = -1;
+                u.got_res("start_queue");
+                u.get_start_queue(&x.msg);
+            });
+            if x.msg == "ok" {
+                set_room_state(rooms, &x.id, RoomState::Queued);
+            }
+        },
+        UserEvent::CancelQueue(x) => {
+            TotalUsers.update_id(&x.room, |u| u.got_res("cancel_queue"));
+            set_room_state(rooms, &x.room, RoomState::Open);
+            for i in TotalUsers.members(&x.room) {
+                TotalUsers.update(i, |u| {
+                    if u.state == UserState::Queued || u.state == UserState::ReadyCheck || u.state == UserState::Ready {
+                        u.cnt = -1;
+                        u.get_cancel_queue();
+                    }
+                });
+            }
+        },
+        UserEvent::Dead(x) => {
+            TotalUsers.update_id(&x.id, |u| {
+                if u.state != UserState::Offline {
+                    info!("user {} dropped by the server in {:?}", u.id, u.state);
+                    u.afk();
+                }
+            });
+        },
+        UserEvent::StartGet(x) => {
+            let started = TotalUsers.update_id(&x.id, |u| {
+                // gone afk, the server has not noticed yet
+                if u.state == UserState::Offline {
+                    return false;
+                }
+                u.start_get();
+                true
+            });
+            if started == Some(true) {
+                let _ = coord.send(CoordMsg::StartGet(x.id));
+            }
+        },
+        UserEvent::PreStart(x) => {
+            if x.msg == "stop queue" {
+                let state = TotalUsers.update_id(&x.id, |u| {
+                    if u.state == UserState::Offline {
+                        return None;
+                    }
+                    u.cnt = -1;
+                    u.get_prestart(false, tx);
+                    Some(u.state)
+                });
+                // the creater tells whether the room was requeued or sent back
+                if let Some(Some(s)) = state {
+                    let state = if s == UserState::Queued { RoomState::Queued } else { RoomState::Open };
+                    set_room_state(rooms, &x.id, state);
+                }
+            } else {
+                set_room_state(rooms, &x.id, RoomState::InGame);
+                for i in TotalUsers.members(&x.id) {
+                    TotalUsers.update(i, |u| {
+                        if u.state == UserState::ReadyCheck {
+                            u.cnt = -1;
+                            u.get_prestart(true, tx);
+                        }
+                    });
+                }
+            }
+        },
+        UserEvent::Ready(x) => {
+            if x.msg == "ready" {
+                let members = TotalUsers.members(&x.room);
+                let party = members.len();
+                for i in members {
+                    TotalUsers.update(i, |u| {
+                        u.cnt = -1;
+                        u.get_ready(party);
+                    });
+                }
+            }
+        },
+        // routed to the coordinator
+        UserEvent::Start(_) | UserEvent::GameSingal(_) | UserEvent::StartGame(_) | UserEvent::GameOver(_) | UserEvent::Shutdown(_) => {}
+    }
+}
+
+/// Games, gauges, the dashboard and the periodic report, for all shards.
+fn run_coordinator(tx: Sender<MqttMsg>, cfg: Config, rx: Receiver<CoordMsg>, shards: Vec<Sender<GameStarted>>, stop: Receiver<()>) {
+    let start = Instant::now();
+    let update1s = tick(Duration::from_secs(1));
+    let update10s = tick(Duration::from_secs(10));
+    let mut games = Games::default();
+    let mut dashboard = Dashboard::new();
+    let mut pending: BTreeMap<u32, PendingGame> = BTreeMap::new();
+    let mut snaps: Vec<Option<Snapshot>> = shards.iter().map(|_| None).collect();
+    let mut playing: Vec<BTreeSet<String>> = shards.iter().map(|_| BTreeSet::new()).collect();
+    let mut shutdown: Option<Sender<()>> = None;
+    loop {
+        select! {
+            recv(stop) -> _ => break,
+            recv(update1s) -> _ => {
+                record::flush();
+                if cfg.dashboard {
+                    let mut states = BTreeMap::new();
+                    let mut rooms = 0;
+                    for s in snaps.iter().flatten() {
+                        for (k, n) in &s.states {
+                            *states.entry(k.clone()).or_insert(0) += n;
+                        }
+                        rooms += s.rooms.0 + s.rooms.1 + s.rooms.2;
+                    }
+                    dashboard.draw(&states, cfg.users, rooms, &games);
+                }
+            }
+            recv(update10s) -> _ => {
+                let all: BTreeSet<String> = playing.iter().flatten().cloned().collect();
+                games.check(&all);
+                let mut r = stats::report(start.elapsed().as_secs());
+                r.seed = cfg.seed;
+                r.shards = cfg.shards.max(1);
+                if !cfg.dashboard {
+                    info!("{:?}", r);
+                }
+                if let Some(path) = &cfg.report {
+                    if let Err(e) = stats::write_report(path, &r) {
+                        warn!("write report failed: {}", e);
+                    }
+                }
+            }
+            recv(rx) -> m => {
+                let m = match m {
+                    Ok(m) => m,
+                    Err(_) => break,
+                };
+                match m {
+                    CoordMsg::Event(UserEvent::Start(x)) => games.start(x.game),
+                    CoordMsg::Event(UserEvent::GameSingal(x)) => {
+                        games.signal(x.game);
+                        let tx = tx.clone();
+                        thread::spawn(move || {
+                            thread::sleep_ms(3000);
+                            send_msg(&tx, MqttMsg{topic:format!("game/{}/send/start_game", x.game),
+                                        msg: format!(r#"{{"game":{},"action":"init"}}"#, x.game),
+                                        from: format!("game/{}", x.game)});
+                        });
+                    }
+                    CoordMsg::Event(UserEvent::StartGame(x)) => {
+                        games.start_game(x.game, x.member.iter().map(|m| m.id.clone()).collect());
+                        if let Some(f) = &cfg.fairness {
+                            let _ = f.try_send(FairnessJob::Started { game: x.game, ids: x.member.iter().map(|m| m.id.clone()).collect() });
+                        }
+                        let mut by_shard: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+                        for m in &x.member {
+                            if let Some(n) = shard_of(&m.id, cfg.users, shards.len()) {
+                                by_shard.entry(n).or_insert_with(Vec::new).push(m.id.clone());
+                            }
+                        }
+                        let game = x.game;
+                        pending.insert(game, PendingGame { res: x, shards: by_shard.len(), seats: BTreeMap::new() });
+                        for (n, ids) in by_shard {
+                            let _ = shards[n].send(GameStarted { game: game, ids: ids });
+                        }
+                        if pending[&game].shards == 0 {
+                            let p = pending.remove(&game).unwrap();
+                            game_over(p, &tx, &cfg);
+                        }
+                    }
+                    CoordMsg::Event(UserEvent::GameOver(x)) => {
+                        games.over(x.game);
+                        if let Some(f) = &cfg.fairness {
+                            let _ = f.try_send(FairnessJob::Over(x.game));
+                        }
+                    }
+                    CoordMsg::Event(UserEvent::Shutdown(done)) => {
+                        info!("shutting down, walking {} users back offline", cfg.users);
+                        // only count reports taken after the shards heard about it
+                        for s in snaps.iter_mut() {
+                            *s = None;
+                        }
+                        shutdown = Some(done);
+                    }
+                    CoordMsg::Event(_) => {}
+                    CoordMsg::StartGet(id) => games.start_get(&id),
+                    CoordMsg::Seats { game, seats } => {
+                        let done = match pending.get_mut(&game) {
+                            Some(p) => {
+                                for s in seats {
+                                    p.seats.insert(s.id.clone(), s);
+                                }
+                                p.shards -= 1;
+                                p.shards == 0
+                            }
+                            None => false,
+                        };
+                        if done {
+                            let p = pending.remove(&game).unwrap();
+                            game_over(p, &tx, &cfg);
+                        }
+                    }
+                    CoordMsg::Snapshot { shard, snap } => {
+                        snaps[shard] = Some(snap);
+                        let (mut login, mut room, mut queue, mut play) = (0, 0, 0, 0);
+                        let (mut open, mut queued, mut in_game) = (0, 0, 0);
+                        for s in snaps.iter().flatten() {
+                            login += s.logged_in;
+                            room += s.in_room;
+                            queue += s.queued;
+                            play += s.playing;
+                            open += s.rooms.0;
+                            queued += s.rooms.1;
+                            in_game += s.rooms.2;
+                        }
+                        stats::set(&stats::USERS_LOGGED_IN, login);
+                        stats::set(&stats::USERS_IN_ROOM, room);
+                        stats::set(&stats::USERS_QUEUED, queue);
+                        stats::set(&stats::USERS_PLAYING, play);
+                        stats::set(&stats::ROOMS_OPEN, open);
+                        stats::set(&stats::ROOMS_QUEUED, queued);
+                        stats::set(&stats::ROOMS_IN_GAME, in_game);
+                        if shutdown.is_some() && snaps.iter().all(|s| s.is_some()) && login == 0 {
+                            info!("every user is offline");
+                            let _ = shutdown.take().unwrap().send(());
+                        }
+                    }
+                    CoordMsg::Playing { shard, ids } => playing[shard] = ids,
+                }
+            }
+        }
+    }
+}
+
+/// Every shard reported its players, make up the result and send it.
+fn game_over(p: PendingGame, tx: &Sender<MqttMsg>, cfg: &Config) {
+    let PendingGame { res: x, seats: players, .. } = p;
+    let mut rng = rng::game_rng(cfg.seed, x.game);
+    if let Some(f) = &cfg.fairness {
+        let seats = x.member.iter().map(|m| {
+            let party = players.get(&m.id).map(|s| s.party.clone()).unwrap_or_else(|| m.id.clone());
+            Seat { id: m.id.clone(), team: m.team, party: party }
+        }).collect();
+        let _ = f.try_send(FairnessJob::Seated { game: x.game, seats: seats });
+    }
+    let mut data: GameOverData = Default::default();
+    let mut data1: GameInfoData = Default::default();
+    let mut r = rng.gen_range(1, 3);
+    for m in &x.member {
+        if m.team == r {
+            data.win.push(m.id.clone());
+        } else {
+            data.lose.push(m.id.clone());
+        }
+        if let Some(seat) = players.get(&m.id) {
+            let mut userinfo: UserInfoData = Default::default();
+            userinfo.id = seat.id.clone();
+            userinfo.hero = seat.hero.clone();
+
+            r = rng.gen_range(13, 16);
+            userinfo.level = r;
+
+            r = rng.gen_range(1000, 3000);
+            userinfo.damage = r;
+
+            userinfo.equ.push("bz".to_string());
+            userinfo.equ.push("uti".to_string());
+            userinfo.equ.push("666".to_string());
+
+            r = rng.gen_range(1000, 3000);
+            userinfo.take_damage = r;
+
+            r = rng.gen_range(500, 1000);
+            userinfo.heal = r;
+
+            r = rng.gen_range(0, 4);
+            userinfo.kill = r;
+
+            r = rng.gen_range(0, 3);
+            userinfo.death = r;
+
+            r = rng.gen_range(0, 5);
+            userinfo.assist = r;
+
+            r = rng.gen_range(0, 4);
+            userinfo.gift.A = r;
+            r = rng.gen_range(0, 4);
+            userinfo.gift.B = r;
+            r = rng.gen_range(0, 4);
+            userinfo.gift.C = r;
+            r = rng.gen_range(0, 4);
+            userinfo.gift.D = r;
+            r = rng.gen_range(0, 4);
+            userinfo.gift.E = r;
+
+            data1.users.push(userinfo);
+        }
+    }
+    data.game = x.game;
+    data1.game = x.game;
+    let results = vec![
+        MqttMsg{topic:format!("game/{}/send/game_over", x.game),
+                msg: json!(data).to_string(), from: format!("game/{}", x.game)},
+        MqttMsg{topic:format!("game/{}/send/game_info", x.game),
+                msg: json!(data1).to_string(), from: format!("game/{}", x.game)},
+    ];
+    match &cfg.verifier {
+        // published once it read the ratings they change
+        Some(v) => v.submit(data, data1, results, tx),
+        None => {
+            for m in results {
+                send_msg(tx, m);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users(rooms: &[&str]) -> UserStore {
+        UserStore::new(rooms.iter().enumerate().map(|(i, room)| User {
+            id: (i + 1).to_string(),
+            room: room.to_string(),
+            ..Default::default()
+        }).collect())
+    }
+
+    fn room(id: &str, ids: &[&str], state: RoomState) -> (String, Rc<RefCell<RoomRecord>>) {
+        let r = RoomRecord { id: id.to_owned(), ids: ids.iter().map(|s| s.to_string()).collect(), state: state };
+        (id.to_owned(), Rc::new(RefCell::new(r)))
+    }
+
+    #[test]
+    fn clean_rooms_drops_stale_rooms_and_members() {
+        // 2 left room 1, room 3's creater is gone, room 4 is closed
+        let store = users(&["1", "", "", "4"]);
+        let mut rooms: IndexMap<_, _> = vec![
+            room("1", &["1", "2"], RoomState::Queued),
+            room("3", &["3"], RoomState::Open),
+            room("4", &["4"], RoomState::Closed),
+        ].into_iter().collect();
+        assert_eq!(clean_rooms(&mut rooms, &store), (0, 1, 0));
+        assert_eq!(rooms.keys().collect::<Vec<_>>(), vec!["1"]);
+        assert_eq!(rooms["1"].borrow().ids, vec!["1".to_string()]);
+    }
+
+    #[test]
+    fn users_and_their_rooms_map_to_shards_by_id() {
+        assert_eq!(shard_of("1", 10, 4), Some(0));
+        assert_eq!(shard_of("6", 10, 4), Some(1));
+        assert_eq!(shard_of("10", 10, 4), Some(1));
+        assert_eq!(shard_of("11", 10, 4), None);
+        assert_eq!(shard_of("0", 10, 4), None);
+        assert_eq!(shard_of("game", 10, 4), None);
+        let cfg = Config { users: 10, ..Default::default() };
+        let ids: Vec<String> = shard_users(1, 4, &cfg).iter().map(|u| u.id.clone()).collect();
+        assert_eq!(ids, vec!["2", "6", "10"]);
+    }
+
+    #[test]
+    fn seat_players_finishes_the_game_and_closes_rooms() {
+        let mut store = users(&["1", "", ""]);
+        store.update(0, |u| u.state = UserState::Playing);
+        let rooms: IndexMap<_, _> = vec![room("1", &["1"], RoomState::InGame)].into_iter().collect();
+        // 2 abandoned and is offline, 9 is on another shard
+        let seats = seat_players(&mut store, &rooms, vec!["1".to_string(), "2".to_string(), "9".to_string()]);
+        assert_eq!(seats.iter().map(|s| (s.id.as_str(), s.party.as_str())).collect::<Vec<_>>(), vec![("1", "1"), ("2", "2")]);
+        assert_eq!(store.get("1").unwrap().state, UserState::Lobby);
+        assert_eq!(store.get("1").unwrap().room, "");
+        assert_eq!(store.get("2").unwrap().state, UserState::Offline);
+        assert_eq!(rooms["1"].borrow().state, RoomState::Closed);
+    }
+
+    #[test]
+    fn game_results_do_not_depend_on_the_order_games_finish() {
+        let pending = |game: u32| {
+            let member = (1..5).map(|i| HeroCell { id: i.to_string(), team: if i < 3 { 1 } else { 2 }, ..Default::default() }).collect();
+            let seats = (1..5).map(|i| (i.to_string(), PlayerSeat { id: i.to_string(), hero: "hero_1".to_string(), party: i.to_string() })).collect();
+            PendingGame { res: StartGameRes { game: game, member: member }, shards: 0, seats: seats }
+        };
+        let cfg = Config { seed: 7, ..Default::default() };
+        let results = |order: &[u32]| {
+            let (tx, rx) = bounded(10);
+            for g in order {
+                game_over(pending(*g), &tx, &cfg);
+            }
+            let mut m: Vec<(String, String)> = rx.try_iter().map(|m| (m.topic, m.msg)).collect();
+            m.sort();
+            m
+        };
+        assert_eq!(results(&[1, 2]), results(&[2, 1]));
+    }
+}
@@ -12,7 +12,7 @@ use ::futures::Future;
 use mysql;
 use std::sync::{Arc, Mutex, Condvar, RwLock};
 use crossbeam_channel::{bounded, tick, Sender, Receiver, select};
-use std::collections::{HashMap, BTreeMap};
+use std::collections::{HashMap, BTreeMap, BTreeSet};
 use std::cell::RefCell;
 use std::rc::Rc;
 use failure::Error;
@@ -21,6 +21,8 @@ use indexmap::IndexMap;
 
 use crate::user::*;
 use crate::msg::*;
+use crate::config::Config;
+use crate::engine;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LoginRes {
@@ -91,6 +93,21 @@ pub struct StartQueueMsg {
     pub msg: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CancelQueueRes {
+    pub msg: String,
+}
+#[derive(Clone, Debug)]
+pub struct CancelQueueMsg {
+    pub room: String,
+    pub msg: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct DeadMsg {
+    pub id: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PreStartRes {
     pub msg: String,
@@ -147,6 +164,11 @@ pub struct GameSingalRes {
     pub game: u32,
 }
 
+#[derive(Clone, Debug)]
+pub struct GameOverMsg {
+    pub game: u32,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct GameOverData {
     pub game: u32,
@@ -203,366 +225,151 @@ pub enum UserEvent {
     ChooseNGHero(UserNGHeroMsg),
     Invite(InviteMsg),
     StartQueue(StartQueueMsg),
+    CancelQueue(CancelQueueMsg),
     PreStart(PreStartMsg),
     Join(JoinMsg),
     StartGame(StartGameRes),
     Start(StartRes),
     StartGet(StartGetMsg),
     GameSingal(GameSingalRes),
+    /// The server took the game's result, it is over.
+    GameOver(GameOverMsg),
     Ready(ReadyData),
+    /// The server dropped this user's session.
+    Dead(DeadMsg),
+    /// Stop playing and walk every user back offline, `done` is signalled once they all are.
+    Shutdown(Sender<()>),
 }
 
-fn get_user(id: &String, users: &BTreeMap<String, Rc<RefCell<User>>>) -> Option<Rc<RefCell<User>>> {
-    let u = users.get(id);
-    if let Some(u) = u {
-        return Some(Rc::clone(u))
-    }
-    None
-}
-
-fn get_users_by_room(room: &String, users: &BTreeMap<String, Rc<RefCell<User>>>) -> Vec<Rc<RefCell<User>>> {
-    let mut user_list: Vec<Rc<RefCell<User>>> = Vec::new();
-    for (id, u) in users {
-        if u.borrow().room == room.to_string() {
-            user_list.push(u.clone());
-        }
-    }
-    user_list
-}
-
-pub fn init(msgtx: Sender<MqttMsg>) -> Sender<UserEvent> {
-    let (tx, rx):(Sender<UserEvent>, Receiver<UserEvent>) = bounded(10000);
-    let start = Instant::now();
-    let update500ms = tick(Duration::from_millis(500));
-    let update100ms = tick(Duration::from_millis(100));
-    let tx2 = tx.clone();
-    
-    thread::spawn(move || {
-        let mut rooms: IndexMap<String, Rc<RefCell<RoomRecord>>> = IndexMap::new();
-        let mut TotalUsers: BTreeMap<String, Rc<RefCell<User>>> = BTreeMap::new();
-        for i in 1..1000 {
-            TotalUsers.insert(i.to_string(),
-                Rc::new(RefCell::new(
-                User {
-                    id: i.to_string(),
-                    hero: "".to_string(),
-                    cnt: -1,
-                    ..Default::default()
-                }
-            )));
-        }
-        let mut tx = msgtx.clone();
-        loop {
-            select! {
-                recv(update500ms) -> _ => {
-                    // println!("rx len: {}, tx len: {}", rx.len(), tx2.len());
-                    for (i, u) in &mut TotalUsers {
-                        //println!("User {} Action", i);
-                        u.borrow_mut().next_action(&mut tx, &mut rooms);
-                    }
-                }
-                recv(rx) -> d => {
-                    if let Ok(d) = d {
-                        match d {
-                            UserEvent::Start(x) => {
-                                
-                            },
-                            UserEvent::GameSingal(x) => {
-                                let mut tx = tx.clone();
-                                thread::spawn(move || {
-                                    thread::sleep_ms(3000);
-                                    tx.try_send(MqttMsg{topic:format!("game/{}/send/start_game", x.game), 
-                                                msg: format!(r#"{{"game":{},"action":"init"}}"#, x.game)}).unwrap();
-                                });
-                            },
-                            UserEvent::StartGame(x) => {
-                                //println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
-                                let mut data: GameOverData = Default::default();
-                                let mut data1: GameInfoData = Default::default();
-                                let mut rng = rand::thread_rng();
-                                let mut r = rng.gen_range(1, 3);
-                                for m in &x.member {
-                                    if m.team == r {
-                                        data.win.push(m.id.clone());
-                                    } else {
-                                        data.lose.push(m.id.clone());
-                                    }
-                                    let u = get_user(&m.id, &TotalUsers);
-                                    if let Some(u) = u {
-                                        let mut userinfo: UserInfoData = Default::default();
-                                        
-                                        u.borrow_mut().game_over();
-                                        userinfo.id = u.borrow().id.clone();
-                                        userinfo.hero = u.borrow().hero.clone();
-
-                                        r = rng.gen_range(13, 16);
-                                        userinfo.level = r;
-
-                                        r = rng.gen_range(1000, 3000);                                        
-                                        userinfo.damage = r;
-
-                                        userinfo.equ.push("bz".to_string());
-                                        userinfo.equ.push("uti".to_string());
-                                        userinfo.equ.push("666".to_string());
-
-                                        r = rng.gen_range(1000, 3000);                                        
-                                        userinfo.take_damage = r;
-
-                                        r = rng.gen_range(500, 1000);
-                                        userinfo.heal = r;
-
-                                        r = rng.gen_range(0, 4);
-                                        userinfo.kill = r;
-
-                                        r = rng.gen_range(0, 3);
-                                        userinfo.death = r;
-
-                                        r = rng.gen_range(0, 5);
-                                        userinfo.assist = r;
-
-                                        r = rng.gen_range(0, 4);
-                                        userinfo.gift.A = r;
-                                        r = rng.gen_range(0, 4);
-                                        userinfo.gift.B = r;
-                                        r = rng.gen_range(0, 4);
-                                        userinfo.gift.C = r;
-                                        r = rng.gen_range(0, 4);
-                                        userinfo.gift.D = r;
-                                        r = rng.gen_range(0, 4);
-                                        userinfo.gift.E = r;
-
-
-                                        data1.users.push(userinfo);
-                                    }
-                                }
-                                data.game = x.game;
-                                data1.game = x.game;
-                                let mut tx = tx.clone();
-                                //thread::spawn(move || {
-                                //    thread::sleep_ms(5000);
-                                tx.try_send(MqttMsg{topic:format!("game/{}/send/game_over", x.game), 
-                                                msg: json!(data).to_string()}).unwrap();
-                                tx.try_send(MqttMsg{topic:format!("game/{}/send/game_info", x.game), 
-                                                msg: json!(data1).to_string()}).unwrap();
-                                //});
-                            },
-                            UserEvent::Join(x) => {
-                                if x.msg == "ok" {
-                                    let u = get_user(&x.id, &TotalUsers);
-                                    if let Some(u) = u {
-                                        u.borrow_mut().cnt = -1;
-                                        u.borrow_mut().get_join(x.room.clone());
-                                    }
-                                    let r = rooms.get(&x.room);
-                                    if let Some(r) = r {
-                                        r.borrow_mut().ids.push(x.id.clone());
-                                    }
-                                }
-                            },
-                            UserEvent::Login(x) => {
-                                let u = get_user(&x.id, &TotalUsers);
-                                if let Some(u) = u {
-                                    u.borrow_mut().cnt = -1;
-                                    u.borrow_mut().get_login();
-                                }
-                            },
-                            UserEvent::Logout(x) => {
-                                let u = get_user(&x.id, &TotalUsers);
-                                if let Some(u) = u {
-                                    u.borrow_mut().cnt = -1;
-                                    u.borrow_mut().get_logout();
-                                }
-                            },
-                            UserEvent::Create(x) => {
-                                if x.msg == "ok" {
-                                    let u = get_user(&x.id, &TotalUsers);
-                                    if let Some(u) = u {
-                                        u.borrow_mut().cnt = -1;
-                                        u.borrow_mut().get_create();
-                                    }
-                                }
-                            },
-                            UserEvent::Close(x) => {
-                                let u = get_user(&x.room, &TotalUsers);
-                                if let Some(u) = u {
-                                    u.borrow_mut().cnt = -1;
-                                    u.borrow_mut().get_close();
-                                }
-                            },
-                            UserEvent::ChooseNGHero(x) => {
-                                let u = get_user(&x.id, &TotalUsers);
-                                if let Some(u) = u {
-                                    u.borrow_mut().cnt = -1;
-                                    u.borrow_mut().get_choose_hero(x.hero);
-                                }
-                            },
-                            UserEvent::Invite(x) => {
-                                let u = get_user(&x.id, &TotalUsers);
-                                if let Some(u) = u {
-                                    u.borrow_mut().cnt = -1;
-                                    u.borrow_mut().get_invite();
-                                }
-                            },
-                            UserEvent::StartQueue(x) => {
-                                let u = get_user(&x.id, &TotalUsers);
-                                if let Some(u) = u {
-                                    u.borrow_mut().cnt = -1;
-                                    u.borrow_mut().get_start_queue();
-                                }
-                            },
-                            UserEvent::StartGet(x) => {
-                                //println!("in");
-                                let u = get_user(&x.id, &TotalUsers);
-                                if let Some(u) = u {
-                                    u.borrow_mut().isPlaying = true;
-                                    u.borrow_mut().start_get();
-                                }
-                            },
-                            UserEvent::PreStart(x) => {
-                                if x.msg == "stop queue" {
-                                    let u = get_user(&x.id, &TotalUsers);
-                                    if let Some(u) = u {
-                                        u.borrow_mut().cnt = -1;
-                                        u.borrow_mut().get_prestart(false, &mut tx);
-                                    }
-                                }
-                                else {
-                                    let r = rooms.get(&x.id);
-                                    if let Some(r) = r {
-                                        for id in &r.borrow().ids {
-                                            let u = get_user(&id, &TotalUsers);
-                                            //println!("room: {}, userid: {}", &x.id, id);
-                                            if let Some(u) = u {
-                                                if u.borrow().isPreStart {
-                                                    continue;
-                                                }
-                                                u.borrow_mut().cnt = -1;
-                                                u.borrow_mut().get_prestart(true, &mut tx);
-                                            }
-                                        }
-                                    }
-                                    println!("PreStart");
-                                }
-                            },
-                            UserEvent::Ready(x) => {
-                                println!("{:?}", x);
-                                if x.msg == "ready" {
-                                    let user_list = get_users_by_room(&x.room, &TotalUsers);
-                                    for u in user_list {
-                                        u.borrow_mut().cnt = -1;
-                                        u.borrow_mut().get_ready();
-                                    }
-                                }
-                            },
-                        }
-                    }
-                }
-            }
-        }
-    });
-    tx
-}
-
-pub fn login(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+/// Start the event engine, responses for the users go into the returned sender.
+pub fn init(msgtx: Sender<MqttMsg>, cfg: Config, stop: Receiver<()>) -> Sender<UserEvent> {
+    engine::start(msgtx, cfg, stop)
+}
+
+pub fn login(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: LoginRes = serde_json::from_value(v)?;
+    let data: LoginRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::Login(LoginMsg{id:id, msg:data.msg}));
     Ok(())
 }
 
-pub fn logout(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn logout(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: LogoutRes = serde_json::from_value(v)?;
+    let data: LogoutRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::Logout(LogoutMsg{id:id, msg:data.msg}));
     Ok(())
 }
 
-pub fn create(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn create(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: CreateRoomRes = serde_json::from_value(v)?;
+    let data: CreateRoomRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::Create(CreateRoomMsg{id:id, msg:data.msg, room:data.room}));
     Ok(())
 }
 
-pub fn close(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn close(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: LogoutRes = serde_json::from_value(v)?;
-    sender.send(UserEvent::Logout(LogoutMsg{id:id, msg:data.msg}));
+    let data: CloseRoomRes = serde_json::from_slice(payload)?;
+    sender.send(UserEvent::Close(CloseRoomMsg{room:id, msg:data.msg}));
+    Ok(())
+}
+
+pub fn dead(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
+{
+    sender.send(UserEvent::Dead(DeadMsg{id:id}));
     Ok(())
 }
 
-pub fn choose_hero(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn cancel_queue(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: UserNGHeroRes = serde_json::from_value(v)?;
+    let data: CancelQueueRes = serde_json::from_slice(payload)?;
+    sender.send(UserEvent::CancelQueue(CancelQueueMsg{room:id, msg:data.msg}));
+    Ok(())
+}
+
+pub fn choose_hero(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
+{
+    let data: UserNGHeroRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::ChooseNGHero(UserNGHeroMsg{id:id, hero: data.hero}));
     Ok(())
 }
 
-pub fn start_queue(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn start_queue(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: StartQueueRes = serde_json::from_value(v)?;
+    let data: StartQueueRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::StartQueue(StartQueueMsg{id:id, msg:data.msg}));
     Ok(())
 }
 
-pub fn start_get(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn start_get(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: StartQueueRes = serde_json::from_value(v)?;
+    let data: StartQueueRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::StartGet(StartGetMsg{id:id, msg:data.msg}));
     Ok(())
 }
 
-pub fn prestart(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn prestart(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: StartQueueRes = serde_json::from_value(v)?;
+    let data: StartQueueRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::PreStart(PreStartMsg{id:id, msg:data.msg}));
     Ok(())
 }
 
-pub fn join(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn join(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: JoinRes = serde_json::from_value(v)?;
+    let data: JoinRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::Join(JoinMsg{id:id, room: data.room, msg:data.msg}));
     Ok(())
 }
 
-pub fn start(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn start(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: StartRes = serde_json::from_value(v)?;
+    let data: StartRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::Start(data));
     Ok(())
 }
 
-pub fn start_game(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn start_game(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: StartGameRes = serde_json::from_value(v)?;
+    let data: StartGameRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::StartGame(data));
     Ok(())
 }
 
-pub fn game_singal(id: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn game_singal(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: GameSingalRes = serde_json::from_value(v)?;
+    let data: GameSingalRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::GameSingal(data));
     Ok(())
 }
 
-pub fn ready(room: String, v: Value, sender: Sender<UserEvent>)
- -> std::result::Result<(), Error>
+pub fn game_over(id: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
+{
+    // the topic names the game
+    let game: u32 = serde_json::from_str(&id)?;
+    sender.send(UserEvent::GameOver(GameOverMsg{game:game}));
+    Ok(())
+}
+
+pub fn ready(room: String, payload: &[u8], sender: &Sender<UserEvent>)
+ -> serde_json::Result<()>
 {
-    let data: ReadyRes = serde_json::from_value(v)?;
+    let data: ReadyRes = serde_json::from_slice(payload)?;
     sender.send(UserEvent::Ready(ReadyData{room: room, msg: data.msg}));
     Ok(())
 }
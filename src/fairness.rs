@@ -0,0 +1,202 @@
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::thread;
+use crossbeam_channel::{bounded, Sender, Receiver};
+use failure::Error;
+use mysql;
+
+use crate::db;
+use crate::stats::{self, GameFairness};
+
+/// One player of a matched game, as start_game listed it.
+#[derive(Clone, Debug)]
+pub struct Seat {
+    pub id: String,
+    pub team: u16,
+    /// room the player queued with, players of one room have to be on the same team
+    pub party: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum FairnessJob {
+    /// start_game listed these players, they are in the game until the server confirms game_over
+    Started { game: u32, ids: Vec<String> },
+    /// the players' shards told their parties, the game can be analyzed
+    Seated { game: u32, seats: Vec<Seat> },
+    Over(u32),
+}
+
+/// Start the analyzer thread. Rating spread is only worked out with a database to read ratings from.
+pub fn start(url: Option<&str>) -> Result<Sender<FairnessJob>, Error> {
+    let pool = match url {
+        Some(url) => Some(db::connect(url)?),
+        None => None,
+    };
+    let (tx, rx): (Sender<FairnessJob>, Receiver<FairnessJob>) = bounded(10000);
+    thread::spawn(move || {
+        // player -> game they are in right now
+        let mut playing: BTreeMap<String, u32> = BTreeMap::new();
+        // game -> its players' other games when start_game listed them
+        let mut busy: BTreeMap<u32, BTreeMap<String, u32>> = BTreeMap::new();
+        for job in rx.iter() {
+            match job {
+                FairnessJob::Started { game, ids } => {
+                    let mut others = BTreeMap::new();
+                    for id in ids {
+                        if let Some(g) = playing.insert(id.clone(), game) {
+                            others.insert(id, g);
+                        }
+                    }
+                    busy.insert(game, others);
+                }
+                FairnessJob::Seated { game, seats } => {
+                    let ratings = match &pool {
+                        Some(pool) => ratings(pool, &seats),
+                        None => None,
+                    };
+                    let others = busy.remove(&game).unwrap_or_default();
+                    let f = analyze(game, &seats, &others, ratings.as_ref());
+                    if !f.fair() {
+                        warn!("game {} matched unfairly: {:?}", game, f);
+                        stats::record_error(format!("game {} matched unfairly", game));
+                    }
+                    stats::record_fairness(f);
+                }
+                FairnessJob::Over(game) => {
+                    playing.retain(|_, g| *g != game);
+                    busy.remove(&game);
+                }
+            }
+        }
+    });
+    info!("analyzing matchmaking{}", if url.is_some() { " with ratings" } else { "" });
+    Ok(tx)
+}
+
+/// Everyone's rating, or None when any of them is unknown.
+fn ratings(pool: &mysql::Pool, seats: &[Seat]) -> Option<BTreeMap<String, i32>> {
+    let mut r = BTreeMap::new();
+    for s in seats {
+        match db::rating(pool, &s.id) {
+            Ok(Some(x)) => { r.insert(s.id.clone(), x); }
+            _ => return None,
+        }
+    }
+    Some(r)
+}
+
+pub fn analyze(game: u32, seats: &[Seat], playing: &BTreeMap<String, u32>, ratings: Option<&BTreeMap<String, i32>>) -> GameFairness {
+    let mut f = GameFairness { game: game, ..Default::default() };
+    for s in seats {
+        *f.teams.entry(s.team).or_insert(0) += 1;
+    }
+    let sizes: Vec<usize> = f.teams.values().cloned().collect();
+    f.uneven_teams = sizes.len() != 2 || sizes[0] != sizes[1];
+
+    let mut parties: BTreeMap<&str, Vec<u16>> = BTreeMap::new();
+    for s in seats {
+        let teams = parties.entry(&s.party).or_insert_with(Vec::new);
+        if !teams.contains(&s.team) {
+            teams.push(s.team);
+        }
+    }
+    f.split_parties = parties.iter().filter(|(_, t)| t.len() > 1).map(|(p, _)| p.to_string()).collect();
+
+    f.double_booked = seats.iter()
+        .filter(|s| playing.get(&s.id).map(|g| *g != game).unwrap_or(false))
+        .map(|s| format!("{} still in game {}", s.id, playing[&s.id]))
+        .collect();
+
+    if let Some(ratings) = ratings {
+        let mut sums: BTreeMap<u16, (f64, usize)> = BTreeMap::new();
+        for s in seats {
+            let e = sums.entry(s.team).or_insert((0.0, 0));
+            e.0 += ratings[&s.id] as f64;
+            e.1 += 1;
+        }
+        let means: Vec<f64> = sums.values().map(|(sum, n)| sum / *n as f64).collect();
+        let hi = means.iter().cloned().fold(std::f64::MIN, f64::max);
+        let lo = means.iter().cloned().fold(std::f64::MAX, f64::min);
+        f.team_ratings = sums.iter().map(|(t, (sum, n))| (*t, sum / *n as f64)).collect();
+        f.rating_spread = if means.is_empty() { None } else { Some(hi - lo) };
+    }
+    f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seat(id: &str, team: u16, party: &str) -> Seat {
+        Seat { id: id.to_owned(), team: team, party: party.to_owned() }
+    }
+
+    #[test]
+    fn even_teams_with_whole_parties_are_fair() {
+        let seats = vec![seat("1", 1, "1"), seat("2", 1, "1"), seat("3", 2, "3"), seat("4", 2, "4")];
+        let ratings: BTreeMap<String, i32> = vec![("1", 1000), ("2", 1200), ("3", 1000), ("4", 1000)]
+            .into_iter().map(|(id, r)| (id.to_owned(), r)).collect();
+        let f = analyze(5, &seats, &BTreeMap::new(), Some(&ratings));
+        assert!(f.fair());
+        assert_eq!(f.team_ratings[&1], 1100.0);
+        assert_eq!(f.rating_spread, Some(100.0));
+    }
+
+    #[test]
+    fn uneven_teams_and_split_party_are_flagged() {
+        let seats = vec![seat("1", 1, "1"), seat("2", 2, "1"), seat("3", 2, "3")];
+        let f = analyze(5, &seats, &BTreeMap::new(), None);
+        assert!(f.uneven_teams);
+        assert_eq!(f.split_parties, vec!["1".to_string()]);
+        assert_eq!(f.rating_spread, None);
+        assert!(!f.fair());
+    }
+
+    #[test]
+    fn player_still_in_another_game_is_double_booked() {
+        let seats = vec![seat("1", 1, "1"), seat("2", 2, "2")];
+        let mut playing = BTreeMap::new();
+        playing.insert("1".to_string(), 4);
+        // already counted in this very game is fine
+        playing.insert("2".to_string(), 5);
+        let f = analyze(5, &seats, &playing, None);
+        assert_eq!(f.double_booked, vec!["1 still in game 4".to_string()]);
+    }
+
+    #[test]
+    fn players_are_in_a_game_until_the_server_ends_it() {
+        // no other test records fairness, but the stats are shared with them
+        let before = stats::fairness();
+        let ids = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let tx = start(None).unwrap();
+        tx.send(FairnessJob::Started { game: 901, ids: ids(&["1", "2"]) }).unwrap();
+        tx.send(FairnessJob::Started { game: 902, ids: ids(&["1", "3"]) }).unwrap();
+        tx.send(FairnessJob::Seated { game: 902, seats: vec![seat("1", 1, "1"), seat("3", 2, "3")] }).unwrap();
+        tx.send(FairnessJob::Over(901)).unwrap();
+        tx.send(FairnessJob::Over(902)).unwrap();
+        tx.send(FairnessJob::Started { game: 903, ids: ids(&["1", "2"]) }).unwrap();
+        tx.send(FairnessJob::Seated { game: 903, seats: vec![seat("1", 1, "1"), seat("2", 2, "2")] }).unwrap();
+        for _ in 0..100 {
+            if stats::fairness().games == before.games + 2 {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let after = stats::fairness();
+        assert_eq!(after.games, before.games + 2);
+        assert_eq!(after.double_booked, before.double_booked + 1);
+        let unfair = stats::unfair_games();
+        assert_eq!(unfair.last().map(|f| (f.game, f.double_booked.clone())), Some((902, vec!["1 still in game 901".to_string()])));
+    }
+
+    #[test]
+    fn summary_rolls_up_every_game() {
+        let mut s = stats::FairnessSummary::default();
+        let spread = |x| GameFairness { rating_spread: Some(x), ..Default::default() };
+        s.add(&spread(100.0));
+        s.add(&spread(300.0));
+        s.add(&GameFairness { uneven_teams: true, ..Default::default() });
+        assert_eq!((s.games, s.unfair, s.uneven_teams, s.rated), (3, 1, 1, 2));
+        assert_eq!((s.mean_spread, s.max_spread), (200.0, 300.0));
+    }
+}
@@ -0,0 +1,214 @@
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+use crate::stats;
+
+/// A game the server has not confirmed game_over for by then is orphaned.
+const ORPHAN_AFTER: Duration = Duration::from_secs(60);
+/// Time from start_get to the game's start_game before a playing user counts as gameless.
+const PLAYING_GRACE: Duration = Duration::from_secs(30);
+/// Finished games are kept this long for late messages, then forgotten.
+const KEEP_OVER: Duration = Duration::from_secs(60);
+/// The shards report who is playing this often, so a list can be that old when it is checked.
+pub const PLAYING_EVERY: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GamePhase {
+    /// start or game_singal came in, start_game sent after a pause
+    Signalled,
+    /// start_game listed the members
+    Started,
+    /// the server confirmed game_over
+    Over,
+}
+
+#[derive(Debug)]
+pub struct GameRecord {
+    pub id: u32,
+    pub phase: GamePhase,
+    pub members: Vec<String>,
+    /// first heard of
+    pub seen: Instant,
+    /// a room's start response named the game
+    pub start: Option<Instant>,
+    pub signal: Option<Instant>,
+    pub start_game: Option<Instant>,
+    pub over: Option<Instant>,
+    /// reported as never finishing, only once
+    pub orphaned: bool,
+}
+
+impl GameRecord {
+    fn new(id: u32) -> GameRecord {
+        GameRecord {
+            id: id,
+            phase: GamePhase::Signalled,
+            members: Vec::new(),
+            seen: Instant::now(),
+            start: None,
+            signal: None,
+            start_game: None,
+            over: None,
+            orphaned: false,
+        }
+    }
+
+    pub fn live(&self) -> bool {
+        self.phase != GamePhase::Over
+    }
+}
+
+/// Every game id the server told us about, from game_singal to game_over.
+#[derive(Debug, Default)]
+pub struct Games {
+    pub games: BTreeMap<u32, GameRecord>,
+    /// users that got start_get and have not shown up in a start_game yet
+    waiting: BTreeMap<String, Instant>,
+    /// users already reported as playing without a game
+    gameless: BTreeSet<String>,
+}
+
+impl Games {
+    pub fn live(&self) -> usize {
+        self.games.values().filter(|g| g.live()).count()
+    }
+
+    fn game(&mut self, id: u32) -> &mut GameRecord {
+        self.games.entry(id).or_insert_with(|| GameRecord::new(id))
+    }
+
+    /// The first of the game's rooms got start.
+    pub fn start(&mut self, id: u32) {
+        let g = self.game(id);
+        if g.start.is_none() {
+            g.start = Some(Instant::now());
+        }
+    }
+
+    pub fn signal(&mut self, id: u32) {
+        self.game(id).signal = Some(Instant::now());
+    }
+
+    pub fn start_get(&mut self, user: &str) {
+        self.waiting.insert(user.to_owned(), Instant::now());
+    }
+
+    /// start_game listed `members`, flag the ones that never got start_get.
+    pub fn start_game(&mut self, id: u32, members: Vec<String>) {
+        let missing: Vec<String> = members.iter()
+            .filter(|m| self.waiting.remove(*m).is_none())
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            warn!("game {} members never got start_get: {:?}", id, missing);
+            stats::inc(&stats::GAMES_MISSING_START_GET);
+            stats::record_error(format!("game {} members never got start_get: {:?}", id, missing));
+        }
+        let g = self.game(id);
+        if g.phase == GamePhase::Over {
+            warn!("start_game for finished game {}", id);
+            stats::record_error(format!("start_game for finished game {}", id));
+            return;
+        }
+        g.phase = GamePhase::Started;
+        g.start_game = Some(Instant::now());
+        g.members = members;
+    }
+
+    pub fn over(&mut self, id: u32) {
+        let g = self.game(id);
+        g.phase = GamePhase::Over;
+        g.over = Some(Instant::now());
+    }
+
+    /// Report orphaned games and users in `playing` with no live game, forget old finished games.
+    pub fn check(&mut self, playing: &BTreeSet<String>) {
+        for g in self.games.values_mut() {
+            if g.live() && !g.orphaned && g.seen.elapsed() > ORPHAN_AFTER {
+                g.orphaned = true;
+                warn!("game {} stuck in {:?} for {}s", g.id, g.phase, g.seen.elapsed().as_secs());
+                stats::inc(&stats::GAMES_ORPHANED);
+                stats::record_error(format!("game {} never finished", g.id));
+            }
+        }
+        self.games.retain(|_, g| g.over.map(|t| t.elapsed() < KEEP_OVER).unwrap_or(true));
+
+        // a stale list still has the players of games that just ended
+        let in_game: BTreeSet<&String> = self.games.values()
+            .filter(|g| g.live() || g.over.map(|t| t.elapsed() < PLAYING_EVERY).unwrap_or(false))
+            .flat_map(|g| g.members.iter())
+            .collect();
+        let mut gameless = BTreeSet::new();
+        for id in playing {
+            if in_game.contains(id) {
+                continue;
+            }
+            let waited = self.waiting.get(id).map(|t| t.elapsed()).unwrap_or(PLAYING_GRACE);
+            if waited >= PLAYING_GRACE {
+                if !self.gameless.contains(id) {
+                    warn!("user {} playing with no live game", id);
+                    stats::inc(&stats::USERS_GAMELESS);
+                    stats::record_error(format!("user {} playing with no live game", id));
+                }
+                gameless.insert(id.clone());
+            }
+        }
+        self.gameless = gameless;
+        // start_get for users that went offline before their game started, newer ones may not be listed yet
+        self.waiting.retain(|id, t| playing.contains(id) || t.elapsed() < PLAYING_EVERY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(v: &[&str]) -> BTreeSet<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn start_game_takes_members_off_the_waiting_list() {
+        let mut g = Games::default();
+        g.start(3);
+        g.signal(3);
+        g.start_get("1");
+        let before = stats::get(&stats::GAMES_MISSING_START_GET);
+        g.start_game(3, vec!["1".to_string(), "2".to_string()]);
+        assert!(stats::get(&stats::GAMES_MISSING_START_GET) > before);
+        assert!(g.waiting.is_empty());
+        let r = &g.games[&3];
+        assert_eq!(r.phase, GamePhase::Started);
+        assert!(r.start.is_some() && r.signal.is_some() && r.start_game.is_some());
+        assert_eq!(g.live(), 1);
+        g.over(3);
+        assert_eq!(g.live(), 0);
+    }
+
+    #[test]
+    fn game_without_game_over_is_orphaned_once() {
+        let mut g = Games::default();
+        g.signal(4);
+        g.games.get_mut(&4).unwrap().seen = Instant::now() - ORPHAN_AFTER - Duration::from_secs(1);
+        g.check(&BTreeSet::new());
+        assert!(g.games[&4].orphaned);
+        g.over(4);
+        g.check(&BTreeSet::new());
+        assert!(g.games.contains_key(&4));
+    }
+
+    #[test]
+    fn playing_user_with_no_live_game_is_gameless() {
+        let mut g = Games::default();
+        g.start_game(5, vec!["1".to_string()]);
+        // just got start_get, its start_game may still be on the way
+        g.start_get("2");
+        g.check(&ids(&["1", "2", "3"]));
+        assert_eq!(g.gameless, ids(&["3"]));
+        // players of a game that just ended can still be on a stale list
+        g.over(5);
+        g.check(&ids(&["1"]));
+        assert!(g.gameless.is_empty());
+    }
+}
@@ -0,0 +1,45 @@
+#![allow(warnings)]
+//! Load generator for the ERPS matchmaking server.
+//!
+//! The bots can also be driven from other crates' tests. The counters behind a report are
+//! process wide and reset when a simulator starts, so run one simulator at a time:
+//!
+//! ```no_run
+//! use erps_test::{Config, Conn, Simulator};
+//!
+//! let conn = Conn { server: "127.0.0.1".into(), port: 1883, username: None, password: None, websocket: None };
+//! let cfg = Config { users: 10, seed: 1, ..Default::default() };
+//! let sim = Simulator::start(&conn, erps_test::sim::generate_client_id(), cfg).unwrap();
+//! std::thread::sleep(std::time::Duration::from_secs(30));
+//! let report = sim.stop();
+//! assert_eq!(report.handler_errors, 0);
+//! ```
+
+pub mod msg;
+pub mod event;
+pub mod user;
+pub mod stats;
+pub mod metrics;
+pub mod config;
+pub mod dashboard;
+pub mod record;
+pub mod rng;
+pub mod profile;
+pub mod db;
+pub mod verify;
+pub mod fairness;
+pub mod game;
+pub mod store;
+pub mod engine;
+pub mod tasks;
+pub mod mock;
+pub mod sim;
+pub mod transport;
+
+pub use crate::config::{Config, Conn};
+pub use crate::event::UserEvent;
+pub use crate::msg::MqttMsg;
+pub use crate::stats::Report;
+pub use crate::sim::Simulator;
+pub use crate::transport::{Transport, Publisher, MqttTransport, MqttWsTransport, ChannelTransport, WebSocketTransport};
+pub use crate::user::{User, UserState, Transition};
@@ -0,0 +1,127 @@
+use log::{info, warn};
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use failure::Error;
+
+use crate::stats::{self, LATENCY_BUCKETS};
+
+/// Serve the counters in `stats` in the Prometheus text format on `0.0.0.0:port`.
+pub fn serve(port: u16) -> Result<(), Error> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    info!("metrics on http://0.0.0.0:{}/metrics", port);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => {
+                    if let Err(e) = handle(s) {
+                        warn!("metrics request failed: {}", e);
+                    }
+                }
+                Err(e) => warn!("metrics accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(mut s: TcpStream) -> Result<(), Error> {
+    // we answer every path with the metrics, the request itself is not interesting
+    let mut buf = [0u8; 1024];
+    let _ = s.read(&mut buf)?;
+    let body = render();
+    write!(s, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(), body)?;
+    Ok(())
+}
+
+pub fn render() -> String {
+    let mut o = String::new();
+    writeln!(o, "# TYPE erps_users gauge").unwrap();
+    for (state, c) in &[("logged_in", &stats::USERS_LOGGED_IN), ("in_room", &stats::USERS_IN_ROOM),
+                        ("queued", &stats::USERS_QUEUED), ("playing", &stats::USERS_PLAYING)] {
+        writeln!(o, "erps_users{{state=\"{}\"}} {}", state, stats::get(c)).unwrap();
+    }
+
+    writeln!(o, "# TYPE erps_rooms gauge").unwrap();
+    for (state, c) in &[("open", &stats::ROOMS_OPEN), ("queued", &stats::ROOMS_QUEUED), ("in_game", &stats::ROOMS_IN_GAME)] {
+        writeln!(o, "erps_rooms{{state=\"{}\"}} {}", state, stats::get(c)).unwrap();
+    }
+    writeln!(o, "# TYPE erps_rooms_stale_total counter").unwrap();
+    writeln!(o, "erps_rooms_stale_total {}", stats::get(&stats::ROOMS_STALE)).unwrap();
+
+    writeln!(o, "# TYPE erps_messages_total counter").unwrap();
+    for ((dir, topic), n) in stats::messages() {
+        writeln!(o, "erps_messages_total{{dir=\"{}\",topic=\"{}\"}} {}", dir, topic, n).unwrap();
+    }
+
+    writeln!(o, "# TYPE erps_publish_queue_depth gauge").unwrap();
+    writeln!(o, "erps_publish_queue_depth {}", stats::get(&stats::PUBLISH_QUEUE_DEPTH)).unwrap();
+    writeln!(o, "# TYPE erps_receive_queue_depth gauge").unwrap();
+    writeln!(o, "erps_receive_queue_depth {}", stats::get(&stats::RECEIVE_QUEUE_DEPTH)).unwrap();
+
+    writeln!(o, "# TYPE erps_response_latency_seconds histogram").unwrap();
+    for (action, h) in stats::latency() {
+        for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+            writeln!(o, "erps_response_latency_seconds_bucket{{action=\"{}\",le=\"{}\"}} {}", action, le, h.buckets[i]).unwrap();
+        }
+        writeln!(o, "erps_response_latency_seconds_bucket{{action=\"{}\",le=\"+Inf\"}} {}", action, h.count).unwrap();
+        writeln!(o, "erps_response_latency_seconds_sum{{action=\"{}\"}} {}", action, h.sum).unwrap();
+        writeln!(o, "erps_response_latency_seconds_count{{action=\"{}\"}} {}", action, h.count).unwrap();
+    }
+    writeln!(o, "# TYPE erps_queue_seconds histogram").unwrap();
+    for q in stats::queue_times() {
+        let labels = format!("phase=\"{}\",party=\"{}\",mode=\"{}\"", q.phase, q.party, q.mode);
+        for (i, le) in stats::QUEUE_BUCKETS.iter().enumerate() {
+            writeln!(o, "erps_queue_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, q.hist.buckets[i]).unwrap();
+        }
+        writeln!(o, "erps_queue_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, q.hist.count).unwrap();
+        writeln!(o, "erps_queue_seconds_sum{{{}}} {}", labels, q.hist.sum).unwrap();
+        writeln!(o, "erps_queue_seconds_count{{{}}} {}", labels, q.hist.count).unwrap();
+    }
+
+    writeln!(o, "# TYPE erps_errors_total counter").unwrap();
+    for (kind, c) in &[("publish_failed", &stats::PUBLISH_FAILED), ("publish_dropped", &stats::PUBLISH_DROPPED),
+                       ("parse", &stats::PARSE_ERRORS), ("topic", &stats::TOPIC_ERRORS),
+                       ("handler", &stats::HANDLER_ERRORS), ("invalid_transition", &stats::INVALID_TRANSITIONS),
+                       ("response_timeout", &stats::RESPONSE_TIMEOUTS)] {
+        writeln!(o, "erps_errors_total{{kind=\"{}\"}} {}", kind, stats::get(c)).unwrap();
+    }
+    writeln!(o, "# TYPE erps_parse_errors_total counter").unwrap();
+    for (topic, n) in stats::parse_errors_by_topic() {
+        writeln!(o, "erps_parse_errors_total{{topic=\"{}\"}} {}", topic, n).unwrap();
+    }
+    writeln!(o, "# TYPE erps_game_lifecycle_errors_total counter").unwrap();
+    for (kind, c) in &[("orphaned", &stats::GAMES_ORPHANED), ("missing_start_get", &stats::GAMES_MISSING_START_GET),
+                       ("user_gameless", &stats::USERS_GAMELESS)] {
+        writeln!(o, "erps_game_lifecycle_errors_total{{kind=\"{}\"}} {}", kind, stats::get(c)).unwrap();
+    }
+    let f = stats::fairness();
+    writeln!(o, "# TYPE erps_games_matched_total counter").unwrap();
+    writeln!(o, "erps_games_matched_total {}", f.games).unwrap();
+    writeln!(o, "# TYPE erps_unfair_games_total counter").unwrap();
+    for (issue, n) in &[("uneven_teams", f.uneven_teams), ("split_parties", f.split_parties), ("double_booked", f.double_booked)] {
+        writeln!(o, "erps_unfair_games_total{{issue=\"{}\"}} {}", issue, n).unwrap();
+    }
+    writeln!(o, "# TYPE erps_team_rating_spread gauge").unwrap();
+    writeln!(o, "erps_team_rating_spread{{stat=\"mean\"}} {}", f.mean_spread).unwrap();
+    writeln!(o, "erps_team_rating_spread{{stat=\"max\"}} {}", f.max_spread).unwrap();
+
+    writeln!(o, "# TYPE erps_games_verified_total counter").unwrap();
+    writeln!(o, "erps_games_verified_total{{res=\"ok\"}} {}", stats::get(&stats::GAMES_VERIFIED)).unwrap();
+    writeln!(o, "erps_games_verified_total{{res=\"mismatch\"}} {}", stats::get(&stats::GAMES_MISMATCHED)).unwrap();
+    writeln!(o, "# TYPE erps_users_shed_total counter").unwrap();
+    writeln!(o, "erps_users_shed_total {}", stats::get(&stats::USERS_SHED)).unwrap();
+    writeln!(o, "# TYPE erps_ready_checks_total counter").unwrap();
+    for (answer, c) in &[("accept", &stats::READY_ACCEPTED), ("decline", &stats::READY_DECLINED), ("timeout", &stats::READY_TIMED_OUT)] {
+        writeln!(o, "erps_ready_checks_total{{answer=\"{}\"}} {}", answer, stats::get(c)).unwrap();
+    }
+    writeln!(o, "# TYPE erps_ready_check_violations_total counter").unwrap();
+    writeln!(o, "erps_ready_check_violations_total {}", stats::get(&stats::READY_VIOLATIONS)).unwrap();
+    writeln!(o, "# TYPE erps_misbehaved_total counter").unwrap();
+    for (profile, n) in stats::misbehaved() {
+        writeln!(o, "erps_misbehaved_total{{profile=\"{}\"}} {}", profile, n).unwrap();
+    }
+    o
+}
@@ -0,0 +1,344 @@
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::str;
+use serde_json::{self, Value, json};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crossbeam_channel::RecvTimeoutError;
+use failure::Error;
+
+use crate::event::{HeroCell, StartGameRes};
+use crate::msg::MqttMsg;
+use crate::transport::Transport;
+
+/// A matched game waiting for its ready check, prestart and result.
+#[derive(Debug, Default)]
+struct MockGame {
+    rooms: Vec<String>,
+    members: Vec<(String, u16)>,
+    accepted: BTreeSet<String>,
+    got: BTreeSet<String>,
+    matched: Option<Instant>,
+    /// everyone accepted, the ready check is over and the prestart_get wait begins
+    started: Option<Instant>,
+}
+
+/// Just enough of the ERPS server for the bots to go round the full cycle.
+#[derive(Debug, Default)]
+struct Mock {
+    team_size: usize,
+    ready_timeout: Duration,
+    penalty: Duration,
+    /// room -> refused the queue until
+    penalised: BTreeMap<String, Instant>,
+    rooms: BTreeMap<String, Vec<String>>,
+    queue: Vec<String>,
+    games: BTreeMap<u32, MockGame>,
+    room_game: BTreeMap<String, u32>,
+    next_game: u32,
+    out: Vec<(String, String)>,
+}
+
+fn field(v: &Value, k: &str) -> String {
+    v[k].as_str().unwrap_or("").to_owned()
+}
+
+impl Mock {
+    fn res(&mut self, topic: String, v: Value) {
+        self.out.push((topic, v.to_string()));
+    }
+
+    fn handle(&mut self, kind: &str, id: &str, action: &str, v: &Value) {
+        match (kind, action) {
+            ("member", "login") => self.res(format!("member/{}/res/login", id), json!({"msg": "ok"})),
+            ("member", "logout") => {
+                self.remove_room(id);
+                self.res(format!("member/{}/res/logout", id), json!({"msg": "ok"}));
+            }
+            ("member", "choose_hero") => {
+                let hero = field(v, "hero");
+                self.res(format!("member/{}/res/choose_hero", id), json!({"id": id, "hero": hero}));
+            }
+            ("room", "create") => {
+                self.rooms.insert(id.to_owned(), vec![id.to_owned()]);
+                self.res(format!("room/{}/res/create", id), json!({"msg": "ok", "room": id}));
+            }
+            ("room", "close") => {
+                self.remove_room(id);
+                self.res(format!("room/{}/res/close", id), json!({"msg": "ok"}));
+            }
+            ("room", "join") => {
+                let room = field(v, "room");
+                let team_size = self.team_size;
+                let ok = !self.room_game.contains_key(&room) && match self.rooms.get_mut(&room) {
+                    Some(r) if r.len() < team_size => {
+                        r.push(id.to_owned());
+                        true
+                    }
+                    _ => false,
+                };
+                let msg = if ok { "ok" } else { "fail" };
+                self.res(format!("room/{}/res/join", id), json!({"room": room, "msg": msg}));
+            }
+            ("room", "start_queue") => {
+                let room = field(v, "room");
+                if self.penalised.get(&room).map(|t| *t > Instant::now()).unwrap_or(false) {
+                    self.res(format!("room/{}/res/start_queue", room), json!({"msg": "penalty"}));
+                    return;
+                }
+                if self.rooms.contains_key(&room) && !self.queue.contains(&room) && !self.room_game.contains_key(&room) {
+                    self.queue.push(room.clone());
+                }
+                self.res(format!("room/{}/res/start_queue", room), json!({"msg": "ok"}));
+                self.try_match();
+            }
+            ("room", "ready") => {
+                let room = field(v, "room");
+                let accept = v["accept"].as_bool().unwrap_or(false);
+                if !accept {
+                    self.penalise(&room);
+                }
+                if let Some(g) = self.room_game.get(&room).cloned() {
+                    if accept {
+                        self.accept(g, id);
+                    } else {
+                        self.decline(g, &[room]);
+                    }
+                }
+            }
+            ("room", "cancel_queue") => {
+                let room = field(v, "room");
+                self.queue.retain(|r| *r != room);
+                if let Some(g) = self.room_game.get(&room).cloned() {
+                    self.decline(g, &[room.clone()]);
+                }
+                self.res(format!("room/{}/res/cancel_queue", room), json!({"msg": "ok"}));
+            }
+            ("room", "prestart_get") => {
+                let room = field(v, "room");
+                if let Some(g) = self.room_game.get(&room).cloned() {
+                    self.prestart_get(g, id);
+                }
+            }
+            ("game", "start_game") => {
+                if let Ok(g) = id.parse::<u32>() {
+                    if let Some(game) = self.games.get(&g) {
+                        let member = game.members.iter().map(|(id, team)| HeroCell {
+                            id: id.clone(),
+                            team: *team,
+                            name: id.clone(),
+                            ..Default::default()
+                        }).collect();
+                        let res = StartGameRes { game: g, member: member };
+                        self.res(format!("game/{}/res/start_game", g), json!(res));
+                    }
+                }
+            }
+            ("game", "game_over") => {
+                if let Ok(g) = id.parse::<u32>() {
+                    if let Some(game) = self.games.remove(&g) {
+                        for r in &game.rooms {
+                            self.room_game.remove(r);
+                            self.rooms.remove(r);
+                        }
+                        self.res(format!("game/{}/res/game_over", g), json!({"msg": "ok"}));
+                    }
+                }
+            }
+            ("game", "game_info") => {}
+            _ => warn!("mock: unhandled {}/{}/send/{}", kind, id, action),
+        }
+    }
+
+    fn remove_room(&mut self, id: &str) {
+        self.rooms.remove(id);
+        self.queue.retain(|r| r != id);
+        for members in self.rooms.values_mut() {
+            members.retain(|m| m != id);
+        }
+    }
+
+    /// Fill two teams from the queue in order, whole rooms only.
+    fn try_match(&mut self) {
+        loop {
+            let mut teams: [Vec<String>; 2] = [Vec::new(), Vec::new()];
+            let mut sizes = [0, 0];
+            for r in &self.queue {
+                let n = self.rooms.get(r).map(|m| m.len()).unwrap_or(0);
+                for t in 0..2 {
+                    if n > 0 && sizes[t] + n <= self.team_size {
+                        teams[t].push(r.clone());
+                        sizes[t] += n;
+                        break;
+                    }
+                }
+                if sizes[0] == self.team_size && sizes[1] == self.team_size {
+                    break;
+                }
+            }
+            if sizes[0] != self.team_size || sizes[1] != self.team_size {
+                return;
+            }
+            self.next_game += 1;
+            let g = self.next_game;
+            let mut game = MockGame { matched: Some(Instant::now()), ..Default::default() };
+            for t in 0..2 {
+                for r in &teams[t] {
+                    for m in &self.rooms[r] {
+                        game.members.push((m.clone(), t as u16 + 1));
+                    }
+                    game.rooms.push(r.clone());
+                    self.room_game.insert(r.clone(), g);
+                }
+            }
+            self.queue.retain(|r| !game.rooms.contains(r));
+            for r in &game.rooms {
+                self.out.push((format!("room/{}/res/ready", r), json!({"msg": "ready"}).to_string()));
+            }
+            self.games.insert(g, game);
+        }
+    }
+
+    fn accept(&mut self, g: u32, id: &str) {
+        let mut start = Vec::new();
+        if let Some(game) = self.games.get_mut(&g) {
+            // a repeated accept does not start the game again
+            if game.accepted.insert(id.to_owned()) && game.accepted.len() == game.members.len() {
+                game.started = Some(Instant::now());
+                start = game.rooms.clone();
+            }
+        }
+        for r in start {
+            self.res(format!("room/{}/res/prestart", r), json!({"msg": "start"}));
+        }
+    }
+
+    /// One decline cancels the game, everyone but the `culprits` goes back to the queue.
+    fn decline(&mut self, g: u32, culprits: &[String]) {
+        if let Some(game) = self.games.remove(&g) {
+            for (m, _) in &game.members {
+                self.res(format!("room/{}/res/prestart", m), json!({"msg": "stop queue"}));
+            }
+            for r in &game.rooms {
+                self.room_game.remove(r);
+                if !culprits.contains(r) {
+                    self.queue.push(r.clone());
+                }
+            }
+            self.try_match();
+        }
+    }
+
+    fn penalise(&mut self, room: &str) {
+        self.queue.retain(|r| r != room);
+        if self.penalty > Duration::from_secs(0) {
+            self.penalised.insert(room.to_owned(), Instant::now() + self.penalty);
+        }
+    }
+
+    /// Cancel ready checks and prestart_get waits nobody finished in time, the rooms that held
+    /// them up are penalised.
+    fn expire(&mut self) {
+        let timeout = self.ready_timeout;
+        let late = |t: Option<Instant>| t.map(|t| t.elapsed() > timeout).unwrap_or(false);
+        let expired: Vec<(u32, bool)> = self.games.iter()
+            .filter(|(_, g)| match g.started {
+                None => late(g.matched),
+                Some(_) => g.got.len() < g.members.len() && late(g.started),
+            })
+            .map(|(id, g)| (*id, g.started.is_some()))
+            .collect();
+        for (g, started) in expired {
+            let culprits: Vec<String> = {
+                let game = &self.games[&g];
+                let done = if started { &game.got } else { &game.accepted };
+                game.rooms.iter()
+                    .filter(|r| self.rooms.get(*r).map(|m| m.iter().any(|id| !done.contains(id))).unwrap_or(true))
+                    .cloned()
+                    .collect()
+            };
+            if started {
+                info!("mock: game {} prestart timed out, {:?} did not send prestart_get", g, culprits);
+            } else {
+                info!("mock: game {} ready check timed out, {:?} did not accept", g, culprits);
+            }
+            for r in &culprits {
+                self.penalise(r);
+            }
+            self.decline(g, &culprits);
+        }
+    }
+
+    fn prestart_get(&mut self, g: u32, id: &str) {
+        let mut start = Vec::new();
+        if let Some(game) = self.games.get_mut(&g) {
+            if game.got.insert(id.to_owned()) && game.got.len() == game.members.len() {
+                start = game.members.iter().map(|(m, _)| m.clone()).collect();
+            }
+        }
+        if !start.is_empty() {
+            for m in start {
+                self.res(format!("room/{}/res/start_get", m), json!({"msg": "ok"}));
+            }
+            self.res(format!("game/{}/res/game_singal", g), json!({"game": g}));
+        }
+    }
+}
+
+pub fn serve(transport: Arc<dyn Transport>, team_size: usize, ready_timeout: Duration, penalty: Duration) -> Result<(), Error> {
+    let incoming = transport.subscribe(0, &["member/+/send/+", "room/+/send/+", "game/+/send/+"])?;
+    let mut publisher = transport.publisher()?;
+    info!("mock server up, team size {}, ready check {}s, decline penalty {}s", team_size, ready_timeout.as_secs(), penalty.as_secs());
+    let mut mock = Mock { team_size: team_size, ready_timeout: ready_timeout, penalty: penalty, ..Default::default() };
+    loop {
+        match incoming.recv_timeout(Duration::from_millis(100)) {
+            Ok(x) => {
+                let parts: Vec<&str> = x.topic.split('/').collect();
+                if parts.len() != 4 {
+                    continue;
+                }
+                let v: Value = match serde_json::from_str(&x.msg) {
+                    Ok(v) => v,
+                    Err(_) => {
+                        warn!("mock: bad payload on {}", x.topic);
+                        continue;
+                    }
+                };
+                mock.handle(parts[0], parts[1], parts[3], &v);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        mock.expire();
+        for (topic, msg) in mock.out.drain(..) {
+            publisher.publish(MqttMsg { topic: topic, msg: msg, ..Default::default() })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prestart_get_held_back_cancels_the_game() {
+        let mut mock = Mock { team_size: 1, penalty: Duration::from_secs(60), ..Default::default() };
+        for r in &["1", "2"] {
+            mock.handle("room", r, "create", &json!({}));
+            mock.handle("room", r, "start_queue", &json!({"room": r}));
+        }
+        for r in &["1", "2"] {
+            mock.handle("room", r, "ready", &json!({"room": r, "accept": true}));
+        }
+        mock.handle("room", "1", "prestart_get", &json!({"room": "1"}));
+        mock.out.clear();
+        mock.expire();
+
+        assert!(mock.games.is_empty());
+        assert_eq!(mock.queue, vec!["1".to_owned()]);
+        assert!(mock.penalised.contains_key("2"));
+        let stopped: Vec<&str> = mock.out.iter().filter(|(_, m)| m.contains("stop queue")).map(|(t, _)| t.as_str()).collect();
+        assert_eq!(stopped, vec!["room/1/res/prestart", "room/2/res/prestart"]);
+    }
+}
@@ -1,8 +1,162 @@
 use serde_derive::{Serialize, Deserialize};
+use crossbeam_channel::{Sender, TrySendError};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+use crate::stats;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct MqttMsg {
     pub topic: String,
     pub msg: String,
+    /// user that sent it, or "game/<id>" for the game messages; one sender's messages go out on one publisher
+    #[serde(skip)]
+    pub from: String,
+}
+
+/// What to do when the publish channel is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backpressure {
+    /// wait until a publisher thread frees a slot
+    Block,
+    /// drop the message and count it
+    Drop,
+    /// drop the message and stop driving the user that sent it
+    Shed,
 }
 
+impl Backpressure {
+    pub fn parse(s: &str) -> Option<Backpressure> {
+        match s {
+            "block" => Some(Backpressure::Block),
+            "drop" => Some(Backpressure::Drop),
+            "shed" => Some(Backpressure::Shed),
+            _ => None,
+        }
+    }
+}
+
+static BACKPRESSURE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_backpressure(b: Backpressure) {
+    let v = match b {
+        Backpressure::Block => 0,
+        Backpressure::Drop => 1,
+        Backpressure::Shed => 2,
+    };
+    BACKPRESSURE.store(v, Ordering::SeqCst);
+}
+
+pub fn backpressure() -> Backpressure {
+    match BACKPRESSURE.load(Ordering::Relaxed) {
+        1 => Backpressure::Drop,
+        2 => Backpressure::Shed,
+        _ => Backpressure::Block,
+    }
+}
+
+/// Queue a message for the publisher threads according to the backpressure policy.
+/// Returns false when the message was not queued.
+pub fn send_msg(tx: &Sender<MqttMsg>, m: MqttMsg) -> bool {
+    match tx.try_send(m) {
+        Ok(_) => true,
+        Err(TrySendError::Full(m)) => {
+            match backpressure() {
+                Backpressure::Block => {
+                    stats::inc(&stats::PUBLISH_BLOCKED);
+                    if tx.send(m).is_err() {
+                        stats::inc(&stats::PUBLISH_DROPPED);
+                        return false;
+                    }
+                    true
+                }
+                Backpressure::Drop | Backpressure::Shed => {
+                    stats::inc(&stats::PUBLISH_DROPPED);
+                    false
+                }
+            }
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            stats::inc(&stats::PUBLISH_DROPPED);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+    use crossbeam_channel::{bounded, Receiver};
+    use indexmap::IndexMap;
+    use lazy_static::lazy_static;
+
+    use crate::user::User;
+
+    lazy_static! {
+        // the policy is process wide, tests that change it take turns
+        static ref POLICY: Mutex<()> = Mutex::new(());
+    }
+
+    fn msg(topic: &str) -> MqttMsg {
+        MqttMsg { topic: topic.to_owned(), ..Default::default() }
+    }
+
+    /// A channel with its only slot taken by "a".
+    fn full() -> (Sender<MqttMsg>, Receiver<MqttMsg>) {
+        let (tx, rx) = bounded(1);
+        tx.send(msg("a")).unwrap();
+        (tx, rx)
+    }
+
+    #[test]
+    fn block_waits_for_a_free_slot() {
+        let _turn = POLICY.lock().unwrap_or_else(|e| e.into_inner());
+        set_backpressure(Backpressure::Block);
+        let (tx, rx) = full();
+        let blocked = stats::get(&stats::PUBLISH_BLOCKED);
+        let reader = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            rx.iter().map(|m| m.topic).collect::<Vec<_>>()
+        });
+        assert!(send_msg(&tx, msg("b")));
+        drop(tx);
+        assert_eq!(reader.join().unwrap(), vec!["a", "b"]);
+        assert_eq!(stats::get(&stats::PUBLISH_BLOCKED), blocked + 1);
+    }
+
+    #[test]
+    fn drop_counts_what_did_not_fit() {
+        let _turn = POLICY.lock().unwrap_or_else(|e| e.into_inner());
+        set_backpressure(Backpressure::Drop);
+        let (tx, rx) = full();
+        let dropped = stats::get(&stats::PUBLISH_DROPPED);
+        assert!(!send_msg(&tx, msg("b")));
+        assert_eq!(stats::get(&stats::PUBLISH_DROPPED), dropped + 1);
+        assert_eq!(rx.try_iter().map(|m| m.topic).collect::<Vec<_>>(), vec!["a"]);
+        set_backpressure(Backpressure::Block);
+    }
+
+    #[test]
+    fn shed_stops_driving_the_user_that_did_not_fit() {
+        let _turn = POLICY.lock().unwrap_or_else(|e| e.into_inner());
+        set_backpressure(Backpressure::Shed);
+        let (mut tx, rx) = full();
+        let (dropped, shed) = (stats::get(&stats::PUBLISH_DROPPED), stats::get(&stats::USERS_SHED));
+        let mut u = User { id: "7".to_owned(), cnt: -1, ..Default::default() };
+        u.login(&mut tx);
+        assert!(u.isShed);
+        assert_eq!(stats::get(&stats::PUBLISH_DROPPED), dropped + 1);
+        assert_eq!(stats::get(&stats::USERS_SHED), shed + 1);
+
+        // room again, but the user stays put
+        rx.try_recv().unwrap();
+        for _ in 0..20 {
+            u.cnt = -1;
+            u.next_action(&mut tx, &mut IndexMap::new());
+        }
+        assert!(rx.is_empty());
+        set_backpressure(Backpressure::Block);
+    }
+}
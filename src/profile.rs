@@ -0,0 +1,141 @@
+use failure::Error;
+use rand::Rng;
+
+use crate::rng::SimRng;
+
+/// How a bot misbehaves, to exercise the server's penalty, requeue and game cancel paths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Profile {
+    Normal,
+    /// walks away instead of answering the ready check
+    AfkReadyCheck,
+    /// accepts the ready check but never sends prestart_get
+    NoPrestartGet,
+    /// disconnects while picking a hero
+    DropHeroPick,
+    /// disconnects in the middle of a game
+    AbandonGame,
+}
+
+impl Default for Profile {
+    fn default() -> Self { Profile::Normal }
+}
+
+impl Profile {
+    pub fn parse(s: &str) -> Option<Profile> {
+        match s {
+            "afk-ready" => Some(Profile::AfkReadyCheck),
+            "no-prestart" => Some(Profile::NoPrestartGet),
+            "drop-pick" => Some(Profile::DropHeroPick),
+            "abandon" => Some(Profile::AbandonGame),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Profile::Normal => "normal",
+            Profile::AfkReadyCheck => "afk-ready",
+            Profile::NoPrestartGet => "no-prestart",
+            Profile::DropHeroPick => "drop-pick",
+            Profile::AbandonGame => "abandon",
+        }
+    }
+}
+
+/// "afk-ready=5,abandon=2" -> percent of the users per profile, the rest play normally.
+pub fn parse_mix(s: &str) -> Result<Vec<(Profile, u32)>, Error> {
+    let mut mix = Vec::new();
+    for part in s.split(',').filter(|p| !p.is_empty()) {
+        let mut kv = part.splitn(2, '=');
+        let name = kv.next().unwrap_or("");
+        let profile = Profile::parse(name)
+            .ok_or_else(|| failure::err_msg(format!("unknown profile {}", name)))?;
+        let pct = kv.next().unwrap_or("").parse::<u32>()?;
+        mix.push((profile, pct));
+    }
+    if mix.iter().map(|(_, pct)| pct).sum::<u32>() > 100 {
+        return Err(failure::err_msg("profile percentages add up to more than 100"));
+    }
+    Ok(mix)
+}
+
+/// Draw a profile for one user from `mix`.
+pub fn pick(mix: &[(Profile, u32)], rng: &mut SimRng) -> Profile {
+    if mix.is_empty() {
+        return Profile::Normal;
+    }
+    let mut r = rng.gen_range(0, 100);
+    for (profile, pct) in mix {
+        if r < *pct {
+            return *profile;
+        }
+        r -= pct;
+    }
+    Profile::Normal
+}
+
+/// How users answer the ready check, and what the server is expected to do about it.
+#[derive(Clone, Copy, Debug)]
+pub struct ReadyCheck {
+    /// percent of ready checks accepted, declined and left to time out
+    pub accept: u32,
+    pub decline: u32,
+    pub timeout: u32,
+    /// seconds the server should refuse to queue a room again after it declined or timed out
+    pub penalty: u64,
+    /// seconds an accepted ready check may stay unresolved
+    pub resolve: u64,
+}
+
+impl Default for ReadyCheck {
+    fn default() -> Self {
+        ReadyCheck { accept: 100, decline: 0, timeout: 0, penalty: 0, resolve: 30 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadyAnswer {
+    Accept,
+    Decline,
+    Timeout,
+}
+
+impl ReadyCheck {
+    /// "accept=80,decline=10,timeout=10,penalty=5", the answers have to add up to 100
+    /// when any is given.
+    pub fn parse(s: &str) -> Result<ReadyCheck, Error> {
+        let mut r = ReadyCheck { accept: 0, ..Default::default() };
+        for part in s.split(',').filter(|p| !p.is_empty()) {
+            let mut kv = part.splitn(2, '=');
+            let k = kv.next().unwrap_or("");
+            let v = kv.next().unwrap_or("");
+            match k {
+                "accept" => r.accept = v.parse()?,
+                "decline" => r.decline = v.parse()?,
+                "timeout" => r.timeout = v.parse()?,
+                "penalty" => r.penalty = v.parse()?,
+                "resolve" => r.resolve = v.parse()?,
+                _ => return Err(failure::err_msg(format!("unknown ready check setting {}", k))),
+            }
+        }
+        if r.accept + r.decline + r.timeout == 0 {
+            r.accept = 100;
+        }
+        if r.accept + r.decline + r.timeout != 100 {
+            return Err(failure::err_msg("ready check accept, decline and timeout have to add up to 100"));
+        }
+        Ok(r)
+    }
+
+    pub fn pick(&self, rng: &mut SimRng) -> ReadyAnswer {
+        let r = rng.gen_range(0, 100);
+        if r < self.accept {
+            ReadyAnswer::Accept
+        } else if r < self.accept + self.decline {
+            ReadyAnswer::Decline
+        } else {
+            ReadyAnswer::Timeout
+        }
+    }
+}
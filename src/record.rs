@@ -0,0 +1,139 @@
+use serde_derive::{Serialize, Deserialize};
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::Sender;
+use failure::Error;
+use lazy_static::lazy_static;
+
+use crate::msg::*;
+
+/// One recorded message, written as a json line.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Record {
+    /// ms since the recording started
+    pub t: u64,
+    /// "s" sent, "r" received
+    pub d: String,
+    pub u: String,
+    pub topic: String,
+    pub msg: String,
+}
+
+struct Recorder {
+    start: Instant,
+    out: BufWriter<File>,
+}
+
+/// A response that did not decode, written as a json line to the dead-letter file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeadLetter {
+    /// ms since the file was opened
+    pub t: u64,
+    pub topic: String,
+    pub error: String,
+    /// invalid utf-8 replaced
+    pub payload: String,
+}
+
+/// Set once a recording starts, so the hot path skips the lock while nothing records.
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+    static ref DEAD_LETTERS: Mutex<Option<Recorder>> = Mutex::new(None);
+}
+
+pub fn start(path: &str) -> Result<(), Error> {
+    let f = File::create(path)?;
+    *RECORDER.lock().unwrap() = Some(Recorder { start: Instant::now(), out: BufWriter::new(f) });
+    RECORDING.store(true, Ordering::Relaxed);
+    info!("recording to {}", path);
+    Ok(())
+}
+
+pub fn start_dead_letters(path: &str) -> Result<(), Error> {
+    let f = File::create(path)?;
+    *DEAD_LETTERS.lock().unwrap() = Some(Recorder { start: Instant::now(), out: BufWriter::new(f) });
+    info!("writing undecodable responses to {}", path);
+    Ok(())
+}
+
+pub fn dead_letter(topic: &str, error: &str, payload: &[u8]) {
+    let mut r = DEAD_LETTERS.lock().unwrap();
+    if let Some(r) = r.as_mut() {
+        let letter = DeadLetter {
+            t: r.start.elapsed().as_millis() as u64,
+            topic: topic.to_owned(),
+            error: error.to_owned(),
+            payload: String::from_utf8_lossy(payload).into_owned(),
+        };
+        if let Ok(line) = serde_json::to_string(&letter) {
+            if let Err(e) = writeln!(r.out, "{}", line) {
+                warn!("dead letter failed: {}", e);
+            }
+        }
+    }
+}
+
+/// member/12/res/login -> 12
+pub fn topic_user(topic: &str) -> &str {
+    topic.split('/').nth(1).unwrap_or("")
+}
+
+pub fn record(d: &str, topic: &str, msg: &str) {
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut r = RECORDER.lock().unwrap();
+    if let Some(r) = r.as_mut() {
+        let rec = Record {
+            t: r.start.elapsed().as_millis() as u64,
+            d: d.to_owned(),
+            u: topic_user(topic).to_owned(),
+            topic: topic.to_owned(),
+            msg: msg.to_owned(),
+        };
+        if let Ok(line) = serde_json::to_string(&rec) {
+            if let Err(e) = writeln!(r.out, "{}", line) {
+                warn!("record failed: {}", e);
+            }
+        }
+    }
+}
+
+pub fn flush() {
+    if let Some(r) = RECORDER.lock().unwrap().as_mut() {
+        let _ = r.out.flush();
+    }
+    if let Some(r) = DEAD_LETTERS.lock().unwrap().as_mut() {
+        let _ = r.out.flush();
+    }
+}
+
+/// Publish every sent message of a recording again, `speed` times faster than recorded.
+pub fn replay(path: &str, speed: f64, tx: &Sender<MqttMsg>) -> Result<usize, Error> {
+    let f = BufReader::new(File::open(path)?);
+    let start = Instant::now();
+    let mut n = 0;
+    for line in f.lines() {
+        let rec: Record = serde_json::from_str(&line?)?;
+        if rec.d != "s" {
+            continue;
+        }
+        let at = Duration::from_millis((rec.t as f64 / speed) as u64);
+        let now = start.elapsed();
+        if at > now {
+            thread::sleep(at - now);
+        }
+        // each sender keeps its publisher and order
+        let from = if rec.topic.starts_with("game/") { format!("game/{}", rec.u) } else { rec.u };
+        tx.send(MqttMsg { topic: rec.topic, msg: rec.msg, from: from })?;
+        n += 1;
+    }
+    Ok(n)
+}
@@ -0,0 +1,40 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::hash::Hasher;
+use std::ops::{Deref, DerefMut};
+use fnv::FnvHasher;
+
+/// Seeded rng that is still `Default`, so `User` can keep deriving it.
+#[derive(Debug)]
+pub struct SimRng(StdRng);
+
+impl Default for SimRng {
+    fn default() -> SimRng {
+        SimRng(StdRng::from_rng(rand::thread_rng()).unwrap())
+    }
+}
+
+impl Deref for SimRng {
+    type Target = StdRng;
+    fn deref(&self) -> &StdRng {
+        &self.0
+    }
+}
+
+impl DerefMut for SimRng {
+    fn deref_mut(&mut self) -> &mut StdRng {
+        &mut self.0
+    }
+}
+
+/// Every user gets its own stream so decisions don't depend on the order users are ticked in.
+pub fn user_rng(seed: u64, id: &str) -> SimRng {
+    let mut h = FnvHasher::default();
+    h.write(id.as_bytes());
+    SimRng(StdRng::seed_from_u64(seed ^ h.finish()))
+}
+
+/// Every game as well, the shards report a game's players in whatever order they get to it.
+pub fn game_rng(seed: u64, game: u32) -> SimRng {
+    user_rng(seed, &format!("game/{}", game))
+}
@@ -0,0 +1,444 @@
+use log::{debug, info, warn, error, trace};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use failure::Error;
+use uuid::Uuid;
+use crossbeam_channel::{bounded, tick, Sender, Receiver, select};
+
+use crate::config::{Config, Conn};
+use crate::event::{self, UserEvent};
+use crate::msg::*;
+use crate::record;
+use crate::stats::{self, Report};
+use crate::transport::{self, Transport};
+
+pub fn generate_client_id() -> String {
+    let s = format!("Elo_Test_{}", Uuid::new_v4());
+    (&s[..16]).to_string()
+}
+
+/// Publisher threads when the run does not ask for a number.
+pub const PUBLISHERS: usize = 8;
+
+/// The publisher out of `n` for a message, every message of one sender gets the same one so
+/// they reach the broker in the order they were sent.
+pub fn partition(m: &MqttMsg, n: usize) -> usize {
+    let key = if m.from.is_empty() { &m.topic } else { &m.from };
+    let mut h = DefaultHasher::new();
+    key.hash(&mut h);
+    (h.finish() % n as u64) as usize
+}
+
+/// Start `n` publisher connections and the thread handing each of them its senders' messages from `rx`.
+pub fn spawn_publishers(n: usize, transport: &Arc<dyn Transport>, rx: &Receiver<MqttMsg>, stop: &Receiver<()>) -> Vec<JoinHandle<()>> {
+    let mut handles = Vec::new();
+    let mut parts = Vec::new();
+    for _ in 0..n.max(1) {
+        let (ptx, prx): (Sender<MqttMsg>, Receiver<MqttMsg>) = bounded(1000);
+        parts.push(ptx);
+        let transport = transport.clone();
+        let stop = stop.clone();
+        handles.push(thread::spawn(move || {
+            let mut publisher = match transport.publisher() {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("publisher connect failed: {}", e);
+                    return;
+                }
+            };
+            loop {
+                select! {
+                    recv(stop) -> _ => break,
+                    recv(prx) -> d => {
+                        // the partitioner is gone and everything it handed over is published
+                        let d = match d {
+                            Ok(d) => d,
+                            Err(_) => break,
+                        };
+                        if d.topic.len() > 2 {
+                            let topic = d.topic.clone();
+                            record::record("s", &topic, &d.msg);
+                            match publisher.publish(d) {
+                                Ok(_) => {
+                                    stats::inc(&stats::PUBLISHED);
+                                    stats::count_msg("send", &topic);
+                                },
+                                Err(x) => {
+                                    stats::inc(&stats::PUBLISH_FAILED);
+                                    stats::record_error(format!("publish failed: {:?}", x));
+                                    debug!("publish failed: {:?}", x);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }));
+    }
+    let rx = rx.clone();
+    let stop = stop.clone();
+    handles.push(thread::spawn(move || {
+        let update = tick(Duration::from_millis(1000));
+        loop {
+            select! {
+                recv(stop) -> _ => break,
+                recv(update) -> _ => {
+                    let size = rx.len() + parts.iter().map(|p| p.len()).sum::<usize>();
+                    stats::set(&stats::PUBLISH_QUEUE_DEPTH, size);
+                },
+                recv(rx) -> d => {
+                    let d = match d {
+                        Ok(d) => d,
+                        Err(_) => break,
+                    };
+                    let i = partition(&d, parts.len());
+                    // a full publisher holds up the rest, like the shared queue did
+                    if parts[i].send(d).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }));
+    handles
+}
+
+/// Every response topic the bots react to.
+pub const TOPICS: &[&str] = &[
+    "member/+/res/login",
+    "member/+/res/logout",
+    "member/+/res/choose_hero",
+    "member/+/res/dead",
+    "room/+/res/create",
+    "room/+/res/close",
+    "room/+/res/start_queue",
+    "room/+/res/cancel_queue",
+    "room/+/res/invite",
+    "room/+/res/join",
+    "room/+/res/accept_join",
+    "room/+/res/kick",
+    "room/+/res/leave",
+    "room/+/res/prestart",
+    //"room/+/res/prestart_get",
+    "room/+/res/start",
+    "room/+/res/start_get",
+    "room/+/res/ready",
+    "game/+/res/game_singal",
+    "game/+/res/game_over",
+    "game/+/res/start_game",
+    "game/+/res/choose",
+    "game/+/res/exit",
+];
+
+/// Topics of subscriber connection `n` out of `subscribers`. With more than one they either share
+/// every subscription through the broker's groups named after `group`, or each takes the users "n + 1", "n + 1 + subscribers", ...
+/// and the first one the game topics.
+pub fn subscriber_topics(n: usize, subscribers: usize, users: usize, group: Option<&str>) -> Vec<String> {
+    if subscribers <= 1 {
+        return TOPICS.iter().map(|t| t.to_string()).collect();
+    }
+    if let Some(group) = group {
+        // a group for each filter, rumqttd keeps only one filter per group
+        return TOPICS.iter().enumerate().map(|(i, t)| format!("$share/{}_{}/{}", group, i, t)).collect();
+    }
+    let mut topics = Vec::new();
+    if n == 0 {
+        topics.extend(TOPICS.iter().filter(|t| t.starts_with("game/")).map(|t| t.to_string()));
+    }
+    for id in (n + 1..users + 1).step_by(subscribers) {
+        topics.push(format!("member/{}/res/+", id));
+        topics.push(format!("room/{}/res/+", id));
+    }
+    topics
+}
+
+/// Whether `topic` matches one of `TOPICS`, a user's "res/+" also brings the ones the bots ignore.
+fn wanted(topic: &str) -> bool {
+    TOPICS.iter().any(|t| {
+        let (mut f, mut p) = (t.split('/'), topic.split('/'));
+        loop {
+            match (f.next(), p.next()) {
+                (None, None) => return true,
+                (Some(a), Some(b)) if a == "+" || a == b => {}
+                _ => return false,
+            }
+        }
+    })
+}
+
+/// Decoder of a response, the same one for everything about one user or room.
+fn decoder(topic: &str, n: usize) -> usize {
+    let key = topic.split('/').nth(1).unwrap_or(topic);
+    let mut h = DefaultHasher::new();
+    key.hash(&mut h);
+    (h.finish() % n as u64) as usize
+}
+
+/// What a decoder thread is handed.
+enum Decode {
+    Msg(MqttMsg),
+    /// answered once everything handed over before it is decoded
+    Fence(Sender<()>),
+}
+
+/// Start `n` decoder threads and the threads handing each of them its responses from every
+/// subscriber connection. Connections must not split one user's responses, or they may be
+/// decoded out of order.
+///
+/// A game's responses are decoded by the connection's own thread once every decoder went through
+/// what came before them, so start_game never overtakes its members' start_get.
+pub fn spawn_decoders(n: usize, incoming: Vec<Receiver<MqttMsg>>, sender: Sender<UserEvent>, stop: &Receiver<()>) -> Vec<JoinHandle<()>> {
+    let mut handles = Vec::new();
+    let mut decoders = Vec::new();
+    for _ in 0..n.max(1) {
+        let (dtx, drx): (Sender<Decode>, Receiver<Decode>) = bounded(10000);
+        decoders.push(dtx);
+        let (sender, stop) = (sender.clone(), stop.clone());
+        handles.push(thread::spawn(move || route(drx, sender, stop)));
+    }
+    for rx in incoming {
+        let (decoders, sender, stop) = (decoders.clone(), sender.clone(), stop.clone());
+        handles.push(thread::spawn(move || {
+            loop {
+                select! {
+                    recv(stop) -> _ => break,
+                    recv(rx) -> m => {
+                        let m = match m {
+                            Ok(m) => m,
+                            Err(_) => break,
+                        };
+                        if !wanted(&m.topic) {
+                            continue;
+                        }
+                        if m.topic.starts_with("game/") {
+                            if !fence(&decoders) {
+                                break;
+                            }
+                            decode(m, &sender);
+                            continue;
+                        }
+                        let i = decoder(&m.topic, decoders.len());
+                        if decoders[i].send(Decode::Msg(m)).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }));
+    }
+    let stop = stop.clone();
+    handles.push(thread::spawn(move || {
+        let update = tick(Duration::from_millis(1000));
+        loop {
+            select! {
+                recv(stop) -> _ => break,
+                recv(update) -> _ => {
+                    stats::set(&stats::RECEIVE_QUEUE_DEPTH, decoders.iter().map(|d| d.len()).sum());
+                }
+            }
+        }
+    }));
+    handles
+}
+
+/// Wait for every decoder to go through what it was handed so far, false once they stopped.
+fn fence(decoders: &[Sender<Decode>]) -> bool {
+    let (done_tx, done) = bounded(decoders.len());
+    for d in decoders {
+        if d.send(Decode::Fence(done_tx.clone())).is_err() {
+            return false;
+        }
+    }
+    drop(done_tx);
+    decoders.iter().all(|_| done.recv().is_ok())
+}
+
+/// Count a response that did not decode and keep it in the dead-letter file.
+pub fn unreadable(topic: &str, error: &str, payload: &[u8]) {
+    stats::parse_error(topic);
+    stats::record_error(format!("can't decode {}: {}", topic, error));
+    warn!("can't decode {}: {}", topic, error);
+    record::dead_letter(topic, error, payload);
+}
+
+/// Run a decoder thread until `stop` closes.
+fn route(incoming: Receiver<Decode>, sender: Sender<UserEvent>, stop: Receiver<()>) {
+    loop {
+        select! {
+            recv(stop) -> _ => break,
+            recv(incoming) -> m => {
+                match m {
+                    Ok(Decode::Msg(x)) => decode(x, &sender),
+                    Ok(Decode::Fence(done)) => { let _ = done.send(()); }
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+}
+
+/// Decode a response straight into the event for its topic and hand it to the event loop.
+fn decode(x: MqttMsg, sender: &Sender<UserEvent>) {
+    let topic_name = x.topic.as_str();
+    let payload = x.msg.as_bytes();
+    stats::count_msg("recv", topic_name);
+    record::record("r", topic_name, &x.msg);
+    // member/12/res/login
+    let parts: Vec<&str> = topic_name.split('/').collect();
+    let action = if parts.len() == 4 && parts[2] == "res" { parts[3] } else { "" };
+    let userid = parts.get(1).cloned().unwrap_or("").to_string();
+    let decoded = match action {
+        "login" => event::login(userid, payload, sender),
+        "logout" => event::logout(userid, payload, sender),
+        "create" => event::create(userid, payload, sender),
+        "close" => event::close(userid, payload, sender),
+        "join" => event::join(userid, payload, sender),
+        "start_queue" => event::start_queue(userid, payload, sender),
+        "cancel_queue" => event::cancel_queue(userid, payload, sender),
+        "choose_hero" => event::choose_hero(userid, payload, sender),
+        "prestart" => event::prestart(userid, payload, sender),
+        "start_get" => {
+            debug!("start get: userid: {} json: {}", userid, x.msg);
+            event::start_get(userid, payload, sender)
+        }
+        "start_game" => event::start_game(userid, payload, sender),
+        "start" => event::start(userid, payload, sender),
+        "game_singal" => event::game_singal(userid, payload, sender),
+        "game_over" => event::game_over(userid, payload, sender),
+        "ready" => event::ready(userid, payload, sender),
+        "dead" => event::dead(userid, payload, sender),
+        // subscribed to, but the bots don't act on them
+        "invite" | "accept_join" | "kick" | "leave" | "choose" | "exit" => Ok(()),
+        _ => {
+            stats::inc(&stats::TOPIC_ERRORS);
+            stats::record_error(format!("unknown topic {}", topic_name));
+            warn!("Topic Error {}", topic_name);
+            return;
+        }
+    };
+    if let Err(e) = decoded {
+        unreadable(topic_name, &e.to_string(), payload);
+    }
+}
+
+/// A running set of simulated users.
+///
+/// Counters live in `stats` and are process wide. Every simulator starts them from zero, so its
+/// report only covers its own run as long as it is the only one running: tests that start
+/// simulators in one process have to take turns, see `tests/reports.rs`.
+pub struct Simulator {
+    start: Instant,
+    seed: u64,
+    shards: usize,
+    stop: Option<Sender<()>>,
+    events: Sender<UserEvent>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Simulator {
+    /// Connect to the broker over MQTT and start driving `cfg.users` bots.
+    pub fn start(conn: &Conn, client_id: String, cfg: Config) -> Result<Simulator, Error> {
+        Simulator::start_with(transport::mqtt(conn, client_id), cfg)
+    }
+
+    /// Start driving `cfg.users` bots over any transport.
+    pub fn start_with(transport: Arc<dyn Transport>, cfg: Config) -> Result<Simulator, Error> {
+        stats::reset();
+        // nothing is ever sent on this channel, dropping the sender stops every thread
+        let (stop_tx, stop) = bounded::<()>(0);
+        let seed = cfg.seed;
+        let shards = cfg.shards.max(1);
+        let subscribers = cfg.subscribers.max(1);
+        // a group of its own, the broker may still hold on to the last run's
+        let group = if cfg.shared_subscriptions { Some(generate_client_id()) } else { None };
+        let mut incoming = Vec::new();
+        for n in 0..subscribers {
+            let topics = subscriber_topics(n, subscribers, cfg.users, group.as_ref().map(|g| g.as_str()));
+            let topics: Vec<&str> = topics.iter().map(|t| t.as_str()).collect();
+            incoming.push(transport.subscribe(n, &topics)?);
+        }
+        let decoders = cfg.decoders;
+        let publishers = if cfg.publishers == 0 { PUBLISHERS } else { cfg.publishers };
+        let (tx, rx):(Sender<MqttMsg>, Receiver<MqttMsg>) = bounded(10000);
+        let sender: Sender<UserEvent> = event::init(tx.clone(), cfg, stop.clone());
+        thread::sleep_ms(100);
+        let mut handles = spawn_publishers(publishers, &transport, &rx, &stop);
+        let events = sender.clone();
+        handles.extend(spawn_decoders(decoders, incoming, sender, &stop));
+        Ok(Simulator {
+            start: Instant::now(),
+            seed: seed,
+            shards: shards,
+            stop: Some(stop_tx),
+            events: events,
+            handles: handles,
+        })
+    }
+
+    pub fn stats(&self) -> Report {
+        let mut r = stats::report(self.start.elapsed().as_secs());
+        r.seed = self.seed;
+        r.shards = self.shards;
+        r
+    }
+
+    /// Stop every bot and wait for the publisher and router threads to finish.
+    pub fn stop(mut self) -> Report {
+        self.stop.take();
+        for h in self.handles.drain(..) {
+            let _ = h.join();
+        }
+        self.stats()
+    }
+
+    /// Walk every user back offline, cancelling queues and closing rooms on the way,
+    /// then stop. Gives up on the stragglers after `timeout`.
+    pub fn shutdown(self, timeout: Duration) -> Report {
+        let (done_tx, done) = bounded::<()>(1);
+        if self.events.send(UserEvent::Shutdown(done_tx)).is_ok() {
+            if done.recv_timeout(timeout).is_err() {
+                warn!("{} users still logged in after {}s", stats::get(&stats::USERS_LOGGED_IN), timeout.as_secs());
+            }
+        }
+        // let the last responses and their publishes through
+        thread::sleep_ms(500);
+        self.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partitioned_subscribers_split_the_users() {
+        assert_eq!(subscriber_topics(0, 3, 7, None), vec![
+            "game/+/res/game_singal", "game/+/res/game_over", "game/+/res/start_game", "game/+/res/choose", "game/+/res/exit",
+            "member/1/res/+", "room/1/res/+", "member/4/res/+", "room/4/res/+", "member/7/res/+", "room/7/res/+",
+        ]);
+        assert_eq!(subscriber_topics(1, 3, 7, None), vec!["member/2/res/+", "room/2/res/+", "member/5/res/+", "room/5/res/+"]);
+        assert_eq!(subscriber_topics(2, 3, 7, None), vec!["member/3/res/+", "room/3/res/+", "member/6/res/+", "room/6/res/+"]);
+        assert!(wanted("member/1/res/login"));
+        assert!(wanted("room/5/res/create"));
+        assert!(wanted("game/9/res/start_game"));
+        // a user's res/+ brings topics the bots never subscribed to, they are dropped before decoding
+        assert!(!wanted("room/1/res/prestart_get"));
+        assert!(!wanted("room/1/res"));
+    }
+
+    #[test]
+    fn shared_subscribers_all_take_every_topic() {
+        let a = subscriber_topics(0, 2, 7, Some("g"));
+        assert_eq!(a, subscriber_topics(1, 2, 7, Some("g")));
+        // a group of its own for each filter
+        assert_eq!(&a[..3], &["$share/g_0/member/+/res/login", "$share/g_1/member/+/res/logout", "$share/g_2/member/+/res/choose_hero"]);
+        assert_eq!(a[a.len() - 1], "$share/g_21/game/+/res/exit");
+        assert_eq!(a.len(), 22);
+        assert_eq!(subscriber_topics(0, 1, 7, Some("g"))[..2], ["member/+/res/login", "member/+/res/logout"]);
+        assert_eq!(subscriber_topics(0, 1, 7, Some("g")).len(), 22);
+    }
+}
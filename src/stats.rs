@@ -0,0 +1,498 @@
+use serde_derive::{Serialize, Deserialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use failure::Error;
+use lazy_static::lazy_static;
+
+pub static PUBLISHED: AtomicUsize = AtomicUsize::new(0);
+pub static PUBLISH_FAILED: AtomicUsize = AtomicUsize::new(0);
+pub static PUBLISH_BLOCKED: AtomicUsize = AtomicUsize::new(0);
+pub static PUBLISH_DROPPED: AtomicUsize = AtomicUsize::new(0);
+pub static USERS_SHED: AtomicUsize = AtomicUsize::new(0);
+pub static PARSE_ERRORS: AtomicUsize = AtomicUsize::new(0);
+pub static TOPIC_ERRORS: AtomicUsize = AtomicUsize::new(0);
+// responses about a user the run does not have
+pub static HANDLER_ERRORS: AtomicUsize = AtomicUsize::new(0);
+pub static INVALID_TRANSITIONS: AtomicUsize = AtomicUsize::new(0);
+// requests the server never answered within the async engine's deadline
+pub static RESPONSE_TIMEOUTS: AtomicUsize = AtomicUsize::new(0);
+pub static GAMES_VERIFIED: AtomicUsize = AtomicUsize::new(0);
+pub static GAMES_MISMATCHED: AtomicUsize = AtomicUsize::new(0);
+// games that never reached game_over
+pub static GAMES_ORPHANED: AtomicUsize = AtomicUsize::new(0);
+// start_game listed someone who never got start_get
+pub static GAMES_MISSING_START_GET: AtomicUsize = AtomicUsize::new(0);
+// users playing while no live game has them
+pub static USERS_GAMELESS: AtomicUsize = AtomicUsize::new(0);
+// rooms we thought open that the server refused joins for
+pub static ROOMS_STALE: AtomicUsize = AtomicUsize::new(0);
+pub static READY_ACCEPTED: AtomicUsize = AtomicUsize::new(0);
+pub static READY_DECLINED: AtomicUsize = AtomicUsize::new(0);
+pub static READY_TIMED_OUT: AtomicUsize = AtomicUsize::new(0);
+// accepted ready checks cancelled by someone else, back in the queue
+pub static READY_REQUEUED: AtomicUsize = AtomicUsize::new(0);
+// decliners refused a new queue during the penalty
+pub static READY_PENALISED: AtomicUsize = AtomicUsize::new(0);
+// the server got a ready check wrong, see the recent errors
+pub static READY_VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+
+// gauges, overwritten by their owner
+pub static PUBLISH_QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+// responses waiting for a decoder
+pub static RECEIVE_QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+pub static USERS_LOGGED_IN: AtomicUsize = AtomicUsize::new(0);
+pub static USERS_IN_ROOM: AtomicUsize = AtomicUsize::new(0);
+pub static USERS_QUEUED: AtomicUsize = AtomicUsize::new(0);
+pub static USERS_PLAYING: AtomicUsize = AtomicUsize::new(0);
+pub static ROOMS_OPEN: AtomicUsize = AtomicUsize::new(0);
+pub static ROOMS_QUEUED: AtomicUsize = AtomicUsize::new(0);
+pub static ROOMS_IN_GAME: AtomicUsize = AtomicUsize::new(0);
+
+/// Every counter and gauge above, for `reset`.
+static ALL: &[&AtomicUsize] = &[
+    &PUBLISHED,
+    &PUBLISH_FAILED,
+    &PUBLISH_BLOCKED,
+    &PUBLISH_DROPPED,
+    &USERS_SHED,
+    &PARSE_ERRORS,
+    &TOPIC_ERRORS,
+    &HANDLER_ERRORS,
+    &INVALID_TRANSITIONS,
+    &RESPONSE_TIMEOUTS,
+    &GAMES_VERIFIED,
+    &GAMES_MISMATCHED,
+    &GAMES_ORPHANED,
+    &GAMES_MISSING_START_GET,
+    &USERS_GAMELESS,
+    &ROOMS_STALE,
+    &READY_ACCEPTED,
+    &READY_DECLINED,
+    &READY_TIMED_OUT,
+    &READY_REQUEUED,
+    &READY_PENALISED,
+    &READY_VIOLATIONS,
+    &PUBLISH_QUEUE_DEPTH,
+    &RECEIVE_QUEUE_DEPTH,
+    &USERS_LOGGED_IN,
+    &USERS_IN_ROOM,
+    &USERS_QUEUED,
+    &USERS_PLAYING,
+    &ROOMS_OPEN,
+    &ROOMS_QUEUED,
+    &ROOMS_IN_GAME,
+];
+
+pub const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+// waiting for a match takes seconds to minutes
+pub const QUEUE_BUCKETS: [f64; 11] = [1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0];
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Histogram {
+    pub buckets: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, v: f64) {
+        self.observe_in(&LATENCY_BUCKETS, v);
+    }
+
+    /// `bounds` has to be the same for every observation of this histogram.
+    pub fn observe_in(&mut self, bounds: &[f64], v: f64) {
+        if self.buckets.len() != bounds.len() {
+            self.buckets = vec![0; bounds.len()];
+        }
+        for (i, le) in bounds.iter().enumerate() {
+            if v <= *le {
+                self.buckets[i] += 1;
+            }
+        }
+        self.sum += v;
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count > 0 { self.sum / self.count as f64 } else { 0.0 }
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile, infinite when it is past the last bucket.
+    pub fn quantile(&self, bounds: &[f64], q: f64) -> f64 {
+        let want = (self.count as f64 * q).ceil() as u64;
+        for (i, n) in self.buckets.iter().enumerate() {
+            if *n >= want {
+                return bounds[i];
+            }
+        }
+        std::f64::INFINITY
+    }
+}
+
+/// Queue time distribution for one phase, party size and mode.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueTimes {
+    /// "match" start_queue to ready check, "start" ready check to start_game
+    pub phase: String,
+    pub party: usize,
+    pub mode: String,
+    pub hist: Histogram,
+}
+
+lazy_static! {
+    // (dir, topic) -> count, a new topic takes the write lock and every message after it only the read lock
+    static ref MESSAGES: RwLock<BTreeMap<(String, String), AtomicU64>> = RwLock::new(BTreeMap::new());
+    static ref LATENCY: Mutex<BTreeMap<String, Histogram>> = Mutex::new(BTreeMap::new());
+    static ref RECENT_ERRORS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+    /// topic pattern -> responses on it that did not decode
+    static ref PARSE_ERRORS_BY_TOPIC: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+    static ref MISMATCHES: Mutex<BTreeMap<u32, Vec<String>>> = Mutex::new(BTreeMap::new());
+    // (phase, party size, mode) -> seconds
+    static ref QUEUE_TIMES: Mutex<BTreeMap<(String, usize, String), Histogram>> = Mutex::new(BTreeMap::new());
+    // every analyzed game rolled up, and the last ones matched unfairly
+    static ref FAIRNESS: Mutex<FairnessSummary> = Mutex::new(FairnessSummary::default());
+    static ref UNFAIR_GAMES: Mutex<VecDeque<GameFairness>> = Mutex::new(VecDeque::new());
+    // profile -> times it kicked in
+    static ref MISBEHAVED: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+}
+
+const RECENT_ERRORS_LEN: usize = 10;
+const UNFAIR_GAMES_LEN: usize = 100;
+
+/// Zero everything for a new run. The stats are process wide, so only one run at a time can
+/// have a report of its own.
+pub fn reset() {
+    for c in ALL {
+        c.store(0, Ordering::Relaxed);
+    }
+    MESSAGES.write().unwrap().clear();
+    LATENCY.lock().unwrap().clear();
+    RECENT_ERRORS.lock().unwrap().clear();
+    PARSE_ERRORS_BY_TOPIC.lock().unwrap().clear();
+    MISMATCHES.lock().unwrap().clear();
+    QUEUE_TIMES.lock().unwrap().clear();
+    *FAIRNESS.lock().unwrap() = FairnessSummary::default();
+    UNFAIR_GAMES.lock().unwrap().clear();
+    MISBEHAVED.lock().unwrap().clear();
+}
+
+pub fn inc(c: &AtomicUsize) {
+    c.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn get(c: &AtomicUsize) -> usize {
+    c.load(Ordering::Relaxed)
+}
+
+pub fn set(c: &AtomicUsize, v: usize) {
+    c.store(v, Ordering::Relaxed);
+}
+
+/// member/12/res/login -> member/+/res/login
+pub fn topic_pattern(topic: &str) -> String {
+    let mut parts: Vec<&str> = topic.split('/').collect();
+    if parts.len() > 1 {
+        parts[1] = "+";
+    }
+    parts.join("/")
+}
+
+pub fn count_msg(dir: &str, topic: &str) {
+    let key = (dir.to_owned(), topic_pattern(topic));
+    if let Some(n) = MESSAGES.read().unwrap().get(&key) {
+        n.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    MESSAGES.write().unwrap().entry(key).or_insert_with(|| AtomicU64::new(0)).fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn messages() -> BTreeMap<(String, String), u64> {
+    MESSAGES.read().unwrap().iter().map(|(k, n)| (k.clone(), n.load(Ordering::Relaxed))).collect()
+}
+
+/// A response on `topic` did not decode.
+pub fn parse_error(topic: &str) {
+    inc(&PARSE_ERRORS);
+    *PARSE_ERRORS_BY_TOPIC.lock().unwrap().entry(topic_pattern(topic)).or_insert(0) += 1;
+}
+
+pub fn parse_errors_by_topic() -> BTreeMap<String, usize> {
+    PARSE_ERRORS_BY_TOPIC.lock().unwrap().clone()
+}
+
+pub fn observe_latency(action: &str, secs: f64) {
+    LATENCY.lock().unwrap().entry(action.to_owned()).or_insert_with(Default::default).observe(secs);
+}
+
+pub fn latency() -> BTreeMap<String, Histogram> {
+    LATENCY.lock().unwrap().clone()
+}
+
+pub fn observe_queue(phase: &str, party: usize, mode: &str, secs: f64) {
+    QUEUE_TIMES.lock().unwrap()
+        .entry((phase.to_owned(), party, mode.to_owned()))
+        .or_insert_with(Default::default)
+        .observe_in(&QUEUE_BUCKETS, secs);
+}
+
+pub fn queue_times() -> Vec<QueueTimes> {
+    QUEUE_TIMES.lock().unwrap().iter()
+        .map(|((phase, party, mode), h)| QueueTimes { phase: phase.clone(), party: *party, mode: mode.clone(), hist: h.clone() })
+        .collect()
+}
+
+/// Keep the last few error messages around for the dashboard.
+pub fn record_error(e: String) {
+    let mut q = RECENT_ERRORS.lock().unwrap();
+    if q.len() >= RECENT_ERRORS_LEN {
+        q.pop_front();
+    }
+    q.push_back(e);
+}
+
+pub fn recent_errors() -> Vec<String> {
+    RECENT_ERRORS.lock().unwrap().iter().cloned().collect()
+}
+
+pub fn record_mismatch(game: u32, m: Vec<String>) {
+    MISMATCHES.lock().unwrap().insert(game, m);
+}
+
+pub fn record_misbehaviour(profile: &str) {
+    *MISBEHAVED.lock().unwrap().entry(profile.to_owned()).or_insert(0) += 1;
+}
+
+pub fn misbehaved() -> BTreeMap<String, usize> {
+    MISBEHAVED.lock().unwrap().clone()
+}
+
+/// How one game came out of matchmaking.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GameFairness {
+    pub game: u32,
+    /// team -> players
+    pub teams: BTreeMap<u16, usize>,
+    pub uneven_teams: bool,
+    /// rooms whose members ended up on different teams
+    pub split_parties: Vec<String>,
+    /// players listed while still in another game
+    pub double_booked: Vec<String>,
+    /// team -> mean rating, empty when some rating is unknown
+    pub team_ratings: BTreeMap<u16, f64>,
+    /// highest minus lowest team mean rating
+    pub rating_spread: Option<f64>,
+}
+
+impl GameFairness {
+    pub fn fair(&self) -> bool {
+        !self.uneven_teams && self.split_parties.is_empty() && self.double_booked.is_empty()
+    }
+}
+
+/// All games' fairness rolled up.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FairnessSummary {
+    pub games: usize,
+    pub unfair: usize,
+    pub uneven_teams: usize,
+    pub split_parties: usize,
+    pub double_booked: usize,
+    /// games with every rating known
+    pub rated: usize,
+    pub mean_spread: f64,
+    pub max_spread: f64,
+}
+
+impl FairnessSummary {
+    /// Roll one more game in.
+    pub fn add(&mut self, g: &GameFairness) {
+        self.games += 1;
+        if !g.fair() {
+            self.unfair += 1;
+        }
+        if g.uneven_teams {
+            self.uneven_teams += 1;
+        }
+        if !g.split_parties.is_empty() {
+            self.split_parties += 1;
+        }
+        if !g.double_booked.is_empty() {
+            self.double_booked += 1;
+        }
+        if let Some(x) = g.rating_spread {
+            self.rated += 1;
+            self.mean_spread += (x - self.mean_spread) / self.rated as f64;
+            self.max_spread = self.max_spread.max(x);
+        }
+    }
+}
+
+pub fn record_fairness(f: GameFairness) {
+    FAIRNESS.lock().unwrap().add(&f);
+    if !f.fair() {
+        let mut q = UNFAIR_GAMES.lock().unwrap();
+        if q.len() >= UNFAIR_GAMES_LEN {
+            q.pop_front();
+        }
+        q.push_back(f);
+    }
+}
+
+pub fn fairness() -> FairnessSummary {
+    FAIRNESS.lock().unwrap().clone()
+}
+
+pub fn unfair_games() -> Vec<GameFairness> {
+    UNFAIR_GAMES.lock().unwrap().iter().cloned().collect()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Report {
+    pub seed: u64,
+    /// the shards the users were split between, parties form within one
+    pub shards: usize,
+    pub elapsed_secs: u64,
+    pub published: usize,
+    pub publish_failed: usize,
+    pub publish_blocked: usize,
+    pub publish_dropped: usize,
+    pub users_shed: usize,
+    pub parse_errors: usize,
+    /// topic pattern -> responses on it that did not decode
+    pub parse_errors_by_topic: BTreeMap<String, usize>,
+    pub topic_errors: usize,
+    pub handler_errors: usize,
+    pub invalid_transitions: usize,
+    pub response_timeouts: usize,
+    pub latency: BTreeMap<String, Histogram>,
+    pub queue_times: Vec<QueueTimes>,
+    pub games_verified: usize,
+    pub games_mismatched: usize,
+    /// game id -> what the database got wrong
+    pub mismatches: BTreeMap<u32, Vec<String>>,
+    pub games_orphaned: usize,
+    pub games_missing_start_get: usize,
+    pub users_gameless: usize,
+    pub rooms_stale: usize,
+    pub fairness: FairnessSummary,
+    /// the last games matched unfairly, oldest first
+    pub games: Vec<GameFairness>,
+    /// profile -> times a misbehaving user acted it out
+    pub misbehaved: BTreeMap<String, usize>,
+    pub ready_accepted: usize,
+    pub ready_declined: usize,
+    pub ready_timed_out: usize,
+    pub ready_requeued: usize,
+    pub ready_penalised: usize,
+    pub ready_violations: usize,
+}
+
+pub fn report(elapsed_secs: u64) -> Report {
+    Report {
+        seed: 0,
+        shards: 0,
+        elapsed_secs: elapsed_secs,
+        published: get(&PUBLISHED),
+        publish_failed: get(&PUBLISH_FAILED),
+        publish_blocked: get(&PUBLISH_BLOCKED),
+        publish_dropped: get(&PUBLISH_DROPPED),
+        users_shed: get(&USERS_SHED),
+        parse_errors: get(&PARSE_ERRORS),
+        parse_errors_by_topic: parse_errors_by_topic(),
+        topic_errors: get(&TOPIC_ERRORS),
+        handler_errors: get(&HANDLER_ERRORS),
+        invalid_transitions: get(&INVALID_TRANSITIONS),
+        response_timeouts: get(&RESPONSE_TIMEOUTS),
+        latency: latency(),
+        queue_times: queue_times(),
+        games_verified: get(&GAMES_VERIFIED),
+        games_mismatched: get(&GAMES_MISMATCHED),
+        mismatches: MISMATCHES.lock().unwrap().clone(),
+        games_orphaned: get(&GAMES_ORPHANED),
+        games_missing_start_get: get(&GAMES_MISSING_START_GET),
+        users_gameless: get(&USERS_GAMELESS),
+        rooms_stale: get(&ROOMS_STALE),
+        fairness: fairness(),
+        games: unfair_games(),
+        misbehaved: misbehaved(),
+        ready_accepted: get(&READY_ACCEPTED),
+        ready_declined: get(&READY_DECLINED),
+        ready_timed_out: get(&READY_TIMED_OUT),
+        ready_requeued: get(&READY_REQUEUED),
+        ready_penalised: get(&READY_PENALISED),
+        ready_violations: get(&READY_VIOLATIONS),
+    }
+}
+
+pub fn write_report(path: &str, r: &Report) -> Result<(), Error> {
+    let mut f = File::create(path)?;
+    f.write_all(serde_json::to_string_pretty(r)?.as_bytes())?;
+    Ok(())
+}
+
+pub fn read_report(path: &str) -> Result<Report, Error> {
+    let mut s = String::new();
+    File::open(path)?.read_to_string(&mut s)?;
+    Ok(serde_json::from_str(&s)?)
+}
+
+/// Human readable form of a report for `erps-test report`.
+pub fn summary(r: &Report) -> String {
+    let mut o = String::new();
+    o.push_str(&format!("seed {}, {} shards, ran {}s\n", r.seed, r.shards, r.elapsed_secs));
+    o.push_str(&format!("published {}  failed {}  blocked {}  dropped {}  users shed {}\n",
+        r.published, r.publish_failed, r.publish_blocked, r.publish_dropped, r.users_shed));
+    o.push_str(&format!("errors: parse {}  topic {}  handler {}  invalid transitions {}  response timeouts {}\n",
+        r.parse_errors, r.topic_errors, r.handler_errors, r.invalid_transitions, r.response_timeouts));
+    for (topic, n) in &r.parse_errors_by_topic {
+        o.push_str(&format!("  can't decode {:<28} {:>8}\n", topic, n));
+    }
+    o.push_str("response latency (mean, count)\n");
+    for (action, h) in &r.latency {
+        o.push_str(&format!("  {:<16} {:>8.3}s {:>8}\n", action, h.mean(), h.count));
+    }
+    if !r.queue_times.is_empty() {
+        o.push_str("queue time (party, mode: mean, p50, p90, count)\n");
+        for q in &r.queue_times {
+            o.push_str(&format!("  {:<6} {}p {:<4} {:>8.1}s {:>6}s {:>6}s {:>8}\n", q.phase, q.party, q.mode,
+                q.hist.mean(), q.hist.quantile(&QUEUE_BUCKETS, 0.5), q.hist.quantile(&QUEUE_BUCKETS, 0.9), q.hist.count));
+        }
+    }
+    o.push_str(&format!("ready checks: accepted {}  declined {}  timed out {}  requeued {}  penalised {}  violations {}\n",
+        r.ready_accepted, r.ready_declined, r.ready_timed_out, r.ready_requeued, r.ready_penalised, r.ready_violations));
+    if !r.misbehaved.is_empty() {
+        o.push_str("misbehaved\n");
+        for (profile, n) in &r.misbehaved {
+            o.push_str(&format!("  {:<16} {:>8}\n", profile, n));
+        }
+    }
+    o.push_str(&format!("games verified {}  mismatched {}\n", r.games_verified, r.games_mismatched));
+    for (game, m) in &r.mismatches {
+        o.push_str(&format!("  game {}\n", game));
+        for x in m {
+            o.push_str(&format!("    {}\n", x));
+        }
+    }
+    o.push_str(&format!("games orphaned {}  missing start_get {}  users playing without a game {}  stale rooms {}\n",
+        r.games_orphaned, r.games_missing_start_get, r.users_gameless, r.rooms_stale));
+    let f = &r.fairness;
+    o.push_str(&format!("matchmaking: games {}  unfair {}  uneven teams {}  split parties {}  double booked {}\n",
+        f.games, f.unfair, f.uneven_teams, f.split_parties, f.double_booked));
+    if f.rated > 0 {
+        o.push_str(&format!("  rating spread over {} games: mean {:.1}  max {:.1}\n", f.rated, f.mean_spread, f.max_spread));
+    }
+    for g in r.games.iter().rev().take(RECENT_ERRORS_LEN) {
+        o.push_str(&format!("  game {} teams {:?}", g.game, g.teams));
+        if !g.split_parties.is_empty() {
+            o.push_str(&format!("  split {:?}", g.split_parties));
+        }
+        if !g.double_booked.is_empty() {
+            o.push_str(&format!("  {:?}", g.double_booked));
+        }
+        o.push('\n');
+    }
+    o
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use crate::user::User;
+
+/// Every bot of a shard in a slab indexed by its number, plus which slots sit in which room.
+///
+/// Users are changed through `update` only, so the room index follows `User::room`.
+#[derive(Debug)]
+pub struct UserStore {
+    users: Vec<User>,
+    /// this store holds bots "shard + 1", "shard + 1 + shards", ...
+    shard: usize,
+    shards: usize,
+    /// room -> slots of its members
+    rooms: HashMap<String, Vec<usize>>,
+}
+
+impl UserStore {
+    /// `users[i]` has to be bot "i + 1".
+    pub fn new(users: Vec<User>) -> UserStore {
+        UserStore::sharded(users, 0, 1)
+    }
+
+    /// `users[i]` has to be bot "i * shards + shard + 1".
+    pub fn sharded(users: Vec<User>, shard: usize, shards: usize) -> UserStore {
+        let mut s = UserStore { users: users, shard: shard, shards: shards, rooms: HashMap::new() };
+        for i in 0..s.users.len() {
+            let room = s.users[i].room.clone();
+            s.enter(i, room);
+        }
+        s
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Slot of the bot with this id, None when it is not in this store.
+    pub fn slot(&self, id: &str) -> Option<usize> {
+        id.parse::<usize>().ok()
+            .filter(|n| *n >= 1 && (n - 1) % self.shards == self.shard)
+            .map(|n| (n - 1) / self.shards)
+            .filter(|i| *i < self.users.len())
+    }
+
+    /// Id of the bot in slot `i`.
+    pub fn slot_id(&self, i: usize) -> String {
+        self.users[i].id.clone()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&User> {
+        self.slot(id).map(|i| &self.users[i])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &User> {
+        self.users.iter()
+    }
+
+    /// Slots of the users in `room`.
+    pub fn members(&self, room: &str) -> Vec<usize> {
+        self.rooms.get(room).cloned().unwrap_or_default()
+    }
+
+    pub fn update<R, F: FnOnce(&mut User) -> R>(&mut self, i: usize, f: F) -> R {
+        let before = self.users[i].room.clone();
+        let r = f(&mut self.users[i]);
+        if self.users[i].room != before {
+            self.leave(i, &before);
+            let room = self.users[i].room.clone();
+            self.enter(i, room);
+        }
+        r
+    }
+
+    /// `update` the user with this id, when there is one.
+    pub fn update_id<R, F: FnOnce(&mut User) -> R>(&mut self, id: &str, f: F) -> Option<R> {
+        self.slot(id).map(|i| self.update(i, f))
+    }
+
+    fn enter(&mut self, i: usize, room: String) {
+        if !room.is_empty() {
+            self.rooms.entry(room).or_insert_with(Vec::new).push(i);
+        }
+    }
+
+    fn leave(&mut self, i: usize, room: &str) {
+        let empty = match self.rooms.get_mut(room) {
+            Some(m) => {
+                m.retain(|s| *s != i);
+                m.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.rooms.remove(room);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str) -> User {
+        User { id: id.to_owned(), cnt: -1, ..Default::default() }
+    }
+
+    #[test]
+    fn room_index_follows_user_room() {
+        let mut users = UserStore::new(vec![user("1"), user("2")]);
+        users.update(0, |u| { u.get_login(); u.get_create(); });
+        users.update_id("2", |u| { u.get_login(); u.get_join("1".to_owned()); });
+        assert_eq!(users.members("1"), vec![0, 1]);
+
+        users.update(1, |u| u.get_logout());
+        assert_eq!(users.members("1"), vec![0]);
+        users.update(0, |u| u.get_close());
+        assert!(users.members("1").is_empty());
+        assert!(users.get("3").is_none());
+    }
+}
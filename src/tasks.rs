@@ -0,0 +1,260 @@
+use log::{error, info, warn};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{Sender, Receiver, select};
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::mpsc as sync_mpsc;
+use futures::unsync::mpsc;
+use indexmap::IndexMap;
+use tokio::runtime::current_thread::Runtime;
+use tokio::timer::{Delay, Interval};
+
+use crate::config::Config;
+use crate::engine::{self, CoordMsg, GameStarted, PlayerSeat, ShardMsg};
+use crate::event::UserEvent;
+use crate::game;
+use crate::msg::MqttMsg;
+use crate::stats;
+use crate::store::UserStore;
+use crate::user::*;
+
+/// Time between two actions of a user.
+const THINK: Duration = Duration::from_millis(500);
+/// A request the server has not answered by then is given up and the user tries again.
+const RESPONSE_DEADLINE: Duration = Duration::from_secs(10);
+
+/// What the users of an async shard share, they all run on the shard's one thread.
+struct Shard {
+    users: RefCell<UserStore>,
+    rooms: RefCell<IndexMap<String, Rc<RefCell<RoomRecord>>>>,
+    room_counts: Cell<(usize, usize, usize)>,
+    shutdown: Cell<bool>,
+    coord: Sender<CoordMsg>,
+}
+
+/// A started game's players on this shard, reported to the coordinator once the last one is seated.
+struct Seating {
+    game: u32,
+    left: usize,
+    seats: Vec<PlayerSeat>,
+}
+
+/// What a user task gets, in the order the shard got it.
+enum UserMsg {
+    Event(UserEvent),
+    /// the user's game started, go through it after everything that came before
+    Seat(Rc<RefCell<Seating>>),
+}
+
+/// What the dispatcher gets, from the router or the coordinator.
+enum Inbound {
+    Shard(ShardMsg),
+    Game(GameStarted),
+}
+
+/// One simulated user: applies its events as they come and acts whenever its think timer fires.
+struct UserTask {
+    slot: usize,
+    shard: Rc<Shard>,
+    inbox: mpsc::UnboundedReceiver<UserMsg>,
+    think: Delay,
+    tx: Sender<MqttMsg>,
+}
+
+impl UserTask {
+    fn act(&mut self) {
+        let shard = &self.shard;
+        let tx = &mut self.tx;
+        let mut rooms = shard.rooms.borrow_mut();
+        shard.users.borrow_mut().update(self.slot, |u| {
+            for action in u.expired(RESPONSE_DEADLINE) {
+                warn!("user {} got no answer to {} in {}s", u.id, action, RESPONSE_DEADLINE.as_secs());
+                stats::inc(&stats::RESPONSE_TIMEOUTS);
+                stats::record_error(format!("user {} got no answer to {}", u.id, action));
+                u.cnt = -1;
+            }
+            engine::act(u, shard.shutdown.get(), tx, &mut rooms);
+        });
+    }
+}
+
+impl Future for UserTask {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            match self.inbox.poll()? {
+                Async::Ready(Some(UserMsg::Event(ev))) => {
+                    let shard = &self.shard;
+                    engine::handle(ev, &mut shard.users.borrow_mut(), &mut shard.rooms.borrow_mut(), &mut self.tx, &shard.coord);
+                }
+                Async::Ready(Some(UserMsg::Seat(seating))) => {
+                    let id = self.shard.users.borrow().slot_id(self.slot);
+                    let seats = engine::seat_players(&mut self.shard.users.borrow_mut(), &self.shard.rooms.borrow(), vec![id]);
+                    seated(&self.shard, &seating, seats);
+                }
+                // the shard is gone
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => break,
+            }
+        }
+        loop {
+            match self.think.poll() {
+                Ok(Async::Ready(())) => {
+                    self.act();
+                    self.think.reset(Instant::now() + THINK);
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    error!("user timer failed: {}", e);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
+
+fn seated(shard: &Shard, seating: &Rc<RefCell<Seating>>, seats: Vec<PlayerSeat>) {
+    let mut s = seating.borrow_mut();
+    s.seats.extend(seats);
+    s.left -= 1;
+    if s.left == 0 {
+        let seats = std::mem::replace(&mut s.seats, Vec::new());
+        let _ = shard.coord.send(CoordMsg::Seats { game: s.game, seats: seats });
+    }
+}
+
+/// Run every `period` on the shard's runtime.
+fn every<F: FnMut() + 'static>(rt: &mut Runtime, period: Duration, mut f: F) {
+    rt.spawn(Interval::new_interval(period)
+        .for_each(move |_| {
+            f();
+            Ok(())
+        })
+        .map_err(|e| error!("shard timer failed: {}", e)));
+}
+
+/// The same shard as `engine::run_shard`, but every user is its own task on a single threaded
+/// tokio runtime, so a shard carries tens of thousands of users without a thread for each.
+pub(crate) fn run_shard(n: usize, shards: usize, tx: Sender<MqttMsg>, cfg: Config, rx: Receiver<ShardMsg>,
+                        games: Receiver<GameStarted>, coord: Sender<CoordMsg>, stop: Receiver<()>) {
+    let mut rt = match Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            error!("shard {} has no runtime: {}", n, e);
+            return;
+        }
+    };
+    // crossbeam to futures, None once the run stops
+    let (fwd, shard_rx) = sync_mpsc::unbounded();
+    {
+        let fwd = fwd.clone();
+        thread::spawn(move || {
+            loop {
+                let m = select! {
+                    recv(rx) -> m => match m {
+                        Ok(m) => Inbound::Shard(m),
+                        Err(_) => break,
+                    },
+                    recv(games) -> m => match m {
+                        Ok(g) => {
+                            // the members' start_get was queued before the game reached the coordinator
+                            for m in rx.try_iter() {
+                                let _ = fwd.unbounded_send(Some(Inbound::Shard(m)));
+                            }
+                            Inbound::Game(g)
+                        }
+                        Err(_) => break,
+                    },
+                };
+                if fwd.unbounded_send(Some(m)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    thread::spawn(move || {
+        let _ = stop.recv();
+        let _ = fwd.unbounded_send(None);
+    });
+
+    let shard = Rc::new(Shard {
+        users: RefCell::new(engine::shard_users(n, shards, &cfg)),
+        rooms: RefCell::new(IndexMap::new()),
+        room_counts: Cell::new((0, 0, 0)),
+        shutdown: Cell::new(false),
+        coord: coord,
+    });
+    let users = shard.users.borrow().len();
+    let mut inboxes = Vec::with_capacity(users);
+    for slot in 0..users {
+        let (itx, irx) = mpsc::unbounded();
+        inboxes.push(itx);
+        // spread the first actions over a think time instead of everyone acting at once
+        let stagger = Duration::from_millis((slot as u64 * 7919) % THINK.as_millis() as u64);
+        rt.spawn(UserTask {
+            slot: slot,
+            shard: shard.clone(),
+            inbox: irx,
+            think: Delay::new(Instant::now() + stagger),
+            tx: tx.clone(),
+        });
+    }
+
+    {
+        let shard = shard.clone();
+        let dashboard = cfg.dashboard;
+        every(&mut rt, THINK, move || {
+            let snap = engine::snapshot(&shard.users.borrow(), shard.room_counts.get(), dashboard);
+            let _ = shard.coord.try_send(CoordMsg::Snapshot { shard: n, snap: snap });
+        });
+    }
+    {
+        let shard = shard.clone();
+        every(&mut rt, Duration::from_secs(1), move || {
+            let counts = engine::clean_rooms(&mut shard.rooms.borrow_mut(), &shard.users.borrow());
+            shard.room_counts.set(counts);
+        });
+    }
+    {
+        let shard = shard.clone();
+        every(&mut rt, game::PLAYING_EVERY, move || {
+            let ids = shard.users.borrow().iter().filter(|u| u.state == UserState::Playing).map(|u| u.id.clone()).collect();
+            let _ = shard.coord.send(CoordMsg::Playing { shard: n, ids: ids });
+        });
+    }
+
+    info!("shard {} running {} user tasks", n, users);
+    let dispatch = shard_rx
+        .take_while(|m| Ok(m.is_some()))
+        .for_each(move |m| {
+            match m {
+                Some(Inbound::Shard(ShardMsg::Event(ev))) => {
+                    let slot = engine::user_key(&ev).and_then(|k| shard.users.borrow().slot(k));
+                    match slot {
+                        Some(i) => { let _ = inboxes[i].unbounded_send(UserMsg::Event(ev)); }
+                        None => warn!("shard {} has no user for {:?}", n, engine::user_key(&ev)),
+                    }
+                }
+                Some(Inbound::Game(GameStarted { game, ids })) => {
+                    let slots: Vec<usize> = ids.iter().filter_map(|id| shard.users.borrow().slot(id)).collect();
+                    let seating = Rc::new(RefCell::new(Seating { game: game, left: slots.len() + 1, seats: Vec::new() }));
+                    for i in slots {
+                        let _ = inboxes[i].unbounded_send(UserMsg::Seat(seating.clone()));
+                    }
+                    // the dispatcher's own share, so a game with no players here is still answered
+                    seated(&shard, &seating, Vec::new());
+                }
+                Some(Inbound::Shard(ShardMsg::Shutdown)) => {
+                    info!("shard {} walking {} users back offline", n, users);
+                    shard.shutdown.set(true);
+                }
+                None => {}
+            }
+            Ok(())
+        });
+    let _ = rt.block_on(dispatch);
+}
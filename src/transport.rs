@@ -0,0 +1,443 @@
+use log::{info, warn, error};
+use std::io::{Cursor, ErrorKind};
+use std::net::TcpStream;
+use std::str;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{bounded, unbounded, Sender, Receiver, TryRecvError};
+use failure::Error;
+use rumqtt::{MqttClient, Notification, QoS};
+use rumqtt::{Packet, Connect, Publish, Subscribe, SubscribeTopic, Protocol, PacketIdentifier, ConnectReturnCode, MqttRead, MqttWrite};
+use serde_derive::{Serialize, Deserialize};
+use tungstenite::{Message, WebSocket};
+use tungstenite::client::{AutoStream, IntoClientRequest};
+use tungstenite::stream::Stream;
+
+use crate::config::Conn;
+use crate::msg::MqttMsg;
+use crate::sim::{self, generate_client_id};
+use crate::stats;
+
+/// How the bots reach the server.
+pub trait Transport: Send + Sync {
+    /// A new publishing connection, every publisher thread asks for its own.
+    fn publisher(&self) -> Result<Box<dyn Publisher>, Error>;
+    /// Open subscriber connection `n` on `topics`, everything received arrives on the returned channel.
+    fn subscribe(&self, n: usize, topics: &[&str]) -> Result<Receiver<MqttMsg>, Error>;
+}
+
+pub trait Publisher: Send {
+    fn publish(&mut self, m: MqttMsg) -> Result<(), Error>;
+}
+
+/// The MQTT transport `conn` asks for, over websocket when it has a websocket url.
+pub fn mqtt(conn: &Conn, client_id: String) -> Arc<dyn Transport> {
+    match &conn.websocket {
+        Some(url) => Arc::new(MqttWsTransport { url: url.clone(), conn: conn.clone(), client_id: client_id }),
+        None => Arc::new(MqttTransport { conn: conn.clone(), client_id: client_id }),
+    }
+}
+
+/// Plain MQTT over tcp to the broker.
+pub struct MqttTransport {
+    pub conn: Conn,
+    /// client id of the first subscribing connection, the others get "_<n>" after it
+    pub client_id: String,
+}
+
+/// Client id of subscriber connection `n`, the broker drops a connection when another takes its id.
+fn subscriber_id(client_id: &str, n: usize) -> String {
+    if n == 0 { client_id.to_owned() } else { format!("{}_{}", client_id, n) }
+}
+
+struct MqttPublisher {
+    client: MqttClient,
+    // rumqtt wants somebody holding the notification side
+    _notifications: Receiver<Notification>,
+}
+
+impl Publisher for MqttPublisher {
+    fn publish(&mut self, m: MqttMsg) -> Result<(), Error> {
+        self.client.publish(m.topic, QoS::AtMostOnce, false, m.msg)?;
+        Ok(())
+    }
+}
+
+impl Transport for MqttTransport {
+    fn publisher(&self) -> Result<Box<dyn Publisher>, Error> {
+        let mut mqtt_options = self.conn.mqtt_options(generate_client_id());
+        mqtt_options = mqtt_options.set_notification_channel_capacity(10000);
+        let (client, notifications) = MqttClient::start(mqtt_options)?;
+        Ok(Box::new(MqttPublisher { client: client, _notifications: notifications }))
+    }
+
+    fn subscribe(&self, n: usize, topics: &[&str]) -> Result<Receiver<MqttMsg>, Error> {
+        let mut mqtt_options = self.conn.mqtt_options(subscriber_id(&self.client_id, n));
+        mqtt_options = mqtt_options.set_notification_channel_capacity(100000);
+        let (mut client, notifications) = MqttClient::start(mqtt_options)?;
+        for t in topics {
+            client.subscribe(*t, QoS::AtMostOnce)?;
+        }
+        let (tx, rx) = unbounded();
+        thread::spawn(move || {
+            // the client lives as long as somebody listens
+            let _client = client;
+            for n in notifications {
+                if let Notification::Publish(x) = n {
+                    match str::from_utf8(&x.payload[..]) {
+                        Ok(msg) => {
+                            if tx.send(MqttMsg { topic: x.topic_name.clone(), msg: msg.to_owned(), ..Default::default() }).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => sim::unreadable(&x.topic_name, &e.to_string(), &x.payload[..]),
+                    }
+                }
+            }
+        });
+        Ok(rx)
+    }
+}
+
+/// In-process transport for driving a test server without a broker.
+///
+/// The server end sees everything the bots publish on `rx` and answers on `tx`, or is itself
+/// a `Transport` for `mock::serve`. Topics are not filtered, the server should only send what
+/// the bots subscribe to, and there is a single subscriber.
+pub struct ChannelTransport {
+    to_server: Sender<MqttMsg>,
+    from_server: Receiver<MqttMsg>,
+}
+
+pub struct ChannelServer {
+    pub rx: Receiver<MqttMsg>,
+    pub tx: Sender<MqttMsg>,
+}
+
+impl ChannelTransport {
+    pub fn pair() -> (ChannelTransport, ChannelServer) {
+        let (to_server, server_rx) = unbounded();
+        let (server_tx, from_server) = unbounded();
+        (ChannelTransport { to_server: to_server, from_server: from_server },
+         ChannelServer { rx: server_rx, tx: server_tx })
+    }
+}
+
+struct ChannelPublisher {
+    tx: Sender<MqttMsg>,
+}
+
+impl Publisher for ChannelPublisher {
+    fn publish(&mut self, m: MqttMsg) -> Result<(), Error> {
+        self.tx.send(m)?;
+        Ok(())
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn publisher(&self) -> Result<Box<dyn Publisher>, Error> {
+        Ok(Box::new(ChannelPublisher { tx: self.to_server.clone() }))
+    }
+
+    fn subscribe(&self, n: usize, topics: &[&str]) -> Result<Receiver<MqttMsg>, Error> {
+        // more would take turns on the one channel and split a user's responses between them
+        if n > 0 {
+            return Err(failure::err_msg("the in-process transport takes one subscriber"));
+        }
+        Ok(self.from_server.clone())
+    }
+}
+
+/// The server's side: subscribing gets what the bots publish, publishing answers them.
+impl Transport for ChannelServer {
+    fn publisher(&self) -> Result<Box<dyn Publisher>, Error> {
+        Ok(Box::new(ChannelPublisher { tx: self.tx.clone() }))
+    }
+
+    fn subscribe(&self, n: usize, topics: &[&str]) -> Result<Receiver<MqttMsg>, Error> {
+        Ok(self.rx.clone())
+    }
+}
+
+/// A server WebSocket gateway speaking json text frames.
+///
+/// Every frame is a `MqttMsg`, `{"topic": .., "msg": ..}`, in both directions.
+/// A subscribing connection first sends `{"subscribe": [topics]}`.
+pub struct WebSocketTransport {
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SubscribeFrame {
+    subscribe: Vec<String>,
+}
+
+/// Open a websocket with a short read timeout, so one thread can both read and write it.
+pub fn ws_connect(url: &str, protocol: Option<&str>) -> Result<WebSocket<AutoStream>, Error> {
+    let mut req = url.into_client_request()?;
+    if let Some(p) = protocol {
+        req.headers_mut().insert("Sec-WebSocket-Protocol", p.parse()?);
+    }
+    let (mut ws, _) = tungstenite::connect(req)?;
+    let timeout = Some(Duration::from_millis(10));
+    match ws.get_mut() {
+        Stream::Plain(s) => s.set_read_timeout(timeout)?,
+        Stream::Tls(s) => s.get_mut().set_read_timeout(timeout)?,
+    }
+    info!("websocket connected to {}", url);
+    Ok(ws)
+}
+
+/// Read one message, None when the read timed out.
+pub fn ws_read(ws: &mut WebSocket<AutoStream>) -> Result<Option<Message>, tungstenite::Error> {
+    match ws.read_message() {
+        Ok(m) => Ok(Some(m)),
+        Err(tungstenite::Error::Io(ref e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Pump `out` into the socket and text frames from the socket into `inc`, until either side goes away.
+fn ws_pump(mut ws: WebSocket<AutoStream>, out: Receiver<MqttMsg>, inc: Option<Sender<MqttMsg>>) {
+    loop {
+        loop {
+            match out.try_recv() {
+                Ok(m) => {
+                    let frame = match serde_json::to_string(&m) {
+                        Ok(f) => f,
+                        Err(_) => continue,
+                    };
+                    if let Err(e) = ws.write_message(Message::Text(frame)) {
+                        error!("websocket write failed: {}", e);
+                        return;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = ws.close(None);
+                    return;
+                }
+            }
+        }
+        match ws_read(&mut ws) {
+            Ok(Some(Message::Text(t))) => {
+                if let Some(inc) = &inc {
+                    match serde_json::from_str::<MqttMsg>(&t) {
+                        Ok(m) => {
+                            if inc.send(m).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => {
+                            stats::inc(&stats::PARSE_ERRORS);
+                            warn!("bad websocket frame: {}", e);
+                        }
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("websocket read failed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Messages a websocket publisher holds before `publish` waits, like rumqtt's request channel.
+const WS_QUEUE: usize = 10000;
+
+struct WebSocketPublisher {
+    tx: Sender<MqttMsg>,
+}
+
+impl Publisher for WebSocketPublisher {
+    fn publish(&mut self, m: MqttMsg) -> Result<(), Error> {
+        self.tx.send(m)?;
+        Ok(())
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn publisher(&self) -> Result<Box<dyn Publisher>, Error> {
+        let ws = ws_connect(&self.url, None)?;
+        let (tx, rx) = bounded(WS_QUEUE);
+        thread::spawn(move || ws_pump(ws, rx, None));
+        Ok(Box::new(WebSocketPublisher { tx: tx }))
+    }
+
+    fn subscribe(&self, n: usize, topics: &[&str]) -> Result<Receiver<MqttMsg>, Error> {
+        let mut ws = ws_connect(&self.url, None)?;
+        let frame = SubscribeFrame { subscribe: topics.iter().map(|t| t.to_string()).collect() };
+        ws.write_message(Message::Text(serde_json::to_string(&frame)?))?;
+        let (inc_tx, inc_rx) = unbounded();
+        // nothing is published on this connection, the sender just has to stay alive
+        let (out_tx, out_rx) = unbounded::<MqttMsg>();
+        thread::spawn(move || {
+            let _out = out_tx;
+            ws_pump(ws, out_rx, Some(inc_tx))
+        });
+        Ok(inc_rx)
+    }
+}
+
+/// MQTT 3.1.1 over the broker's websocket listener, the path the game clients take.
+///
+/// `url` is ws://host:8083/mqtt or wss://..., every binary frame carries MQTT packets.
+pub struct MqttWsTransport {
+    pub url: String,
+    /// username and password, server and port are not used
+    pub conn: Conn,
+    /// client id of the first subscribing connection, the others get "_<n>" after it
+    pub client_id: String,
+}
+
+// same as rumqtt gets in Conn::mqtt_options
+const KEEP_ALIVE: u16 = 100;
+
+fn mqtt_frame(p: &Packet) -> Result<Message, Error> {
+    let mut buf = Cursor::new(Vec::new());
+    buf.write_packet(p)?;
+    Ok(Message::Binary(buf.into_inner()))
+}
+
+/// Length of the first packet in `buf`, None until all of it arrived.
+fn packet_len(buf: &[u8]) -> Option<usize> {
+    let mut len = 0;
+    for i in 0..4 {
+        let b = *buf.get(1 + i)? as usize;
+        len += (b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            let total = 2 + i + len;
+            return if buf.len() >= total { Some(total) } else { None };
+        }
+    }
+    None
+}
+
+impl MqttWsTransport {
+    /// Websocket with a finished MQTT connect.
+    fn connect(&self, client_id: String) -> Result<WebSocket<AutoStream>, Error> {
+        let mut ws = ws_connect(&self.url, Some("mqtt"))?;
+        let connect = Connect {
+            protocol: Protocol::MQTT(4),
+            keep_alive: KEEP_ALIVE,
+            client_id: client_id,
+            clean_session: true,
+            last_will: None,
+            username: self.conn.username.clone(),
+            password: self.conn.username.as_ref().map(|_| self.conn.password.clone().unwrap_or_default()),
+        };
+        ws.write_message(mqtt_frame(&Packet::Connect(connect))?)?;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Some(Message::Binary(b)) = ws_read(&mut ws)? {
+                return match Cursor::new(b).read_packet()? {
+                    Packet::Connack(c) if c.code == ConnectReturnCode::Accepted => Ok(ws),
+                    p => Err(failure::err_msg(format!("mqtt connect refused: {:?}", p))),
+                };
+            }
+        }
+        Err(failure::err_msg("no connack from the broker"))
+    }
+}
+
+/// Like `ws_pump` but with MQTT packets in the frames, and pinging the broker when idle.
+fn mqtt_ws_pump(mut ws: WebSocket<AutoStream>, out: Receiver<MqttMsg>, inc: Option<Sender<MqttMsg>>) {
+    let mut buf: Vec<u8> = Vec::new();
+    let mut last_write = Instant::now();
+    loop {
+        let mut frames = Vec::new();
+        loop {
+            match out.try_recv() {
+                Ok(m) => frames.push(Packet::Publish(Publish {
+                    dup: false,
+                    qos: QoS::AtMostOnce,
+                    retain: false,
+                    topic_name: m.topic,
+                    pkid: None,
+                    payload: Arc::new(m.msg.into_bytes()),
+                })),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if let Ok(f) = mqtt_frame(&Packet::Disconnect) {
+                        let _ = ws.write_message(f);
+                    }
+                    let _ = ws.close(None);
+                    return;
+                }
+            }
+        }
+        if frames.is_empty() && last_write.elapsed() > Duration::from_secs(KEEP_ALIVE as u64 / 2) {
+            frames.push(Packet::Pingreq);
+        }
+        for p in frames {
+            let sent = mqtt_frame(&p).and_then(|f| ws.write_message(f).map_err(Error::from));
+            if let Err(e) = sent {
+                error!("mqtt websocket write failed: {}", e);
+                return;
+            }
+            last_write = Instant::now();
+        }
+        match ws_read(&mut ws) {
+            Ok(Some(Message::Binary(b))) => buf.extend_from_slice(&b),
+            Ok(_) => {}
+            Err(e) => {
+                error!("mqtt websocket read failed: {}", e);
+                return;
+            }
+        }
+        while let Some(n) = packet_len(&buf) {
+            let p = (&buf[..n]).read_packet();
+            buf.drain(..n);
+            match p {
+                Ok(Packet::Publish(x)) => {
+                    if let Some(inc) = &inc {
+                        match str::from_utf8(&x.payload[..]) {
+                            Ok(msg) => {
+                                if inc.send(MqttMsg { topic: x.topic_name, msg: msg.to_owned(), ..Default::default() }).is_err() {
+                                    return;
+                                }
+                            }
+                            Err(e) => sim::unreadable(&x.topic_name, &e.to_string(), &x.payload[..]),
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    stats::inc(&stats::PARSE_ERRORS);
+                    warn!("bad mqtt packet from the broker: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Transport for MqttWsTransport {
+    fn publisher(&self) -> Result<Box<dyn Publisher>, Error> {
+        let ws = self.connect(generate_client_id())?;
+        let (tx, rx) = bounded(WS_QUEUE);
+        thread::spawn(move || mqtt_ws_pump(ws, rx, None));
+        Ok(Box::new(WebSocketPublisher { tx: tx }))
+    }
+
+    fn subscribe(&self, n: usize, topics: &[&str]) -> Result<Receiver<MqttMsg>, Error> {
+        let mut ws = self.connect(subscriber_id(&self.client_id, n))?;
+        // a connection subscribing every user of its slice would not fit one packet
+        for (i, chunk) in topics.chunks(1000).enumerate() {
+            let sub = Subscribe {
+                pkid: PacketIdentifier(i as u16 + 1),
+                topics: chunk.iter().map(|t| SubscribeTopic { topic_path: t.to_string(), qos: QoS::AtMostOnce }).collect(),
+            };
+            ws.write_message(mqtt_frame(&Packet::Subscribe(sub))?)?;
+        }
+        let (inc_tx, inc_rx) = unbounded();
+        let (out_tx, out_rx) = unbounded::<MqttMsg>();
+        thread::spawn(move || {
+            let _out = out_tx;
+            mqtt_ws_pump(ws, out_rx, Some(inc_tx))
+        });
+        Ok(inc_rx)
+    }
+}
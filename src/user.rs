@@ -1,11 +1,84 @@
 use log::{info, warn, error, trace};
 use crate::msg::*;
+use crate::stats;
+use crate::rng::SimRng;
+use crate::profile::{Profile, ReadyCheck, ReadyAnswer};
+use crate::db::TEST_HEROES;
 use crossbeam_channel::{bounded, tick, Sender, Receiver, select};
 use rand::Rng;
 use std::cell::RefCell;
 use std::rc::Rc;
 use rand::seq::SliceRandom;
 use indexmap::IndexMap;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Where a user is in the lobby -> room -> queue -> ready -> game -> over cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum UserState {
+    Offline,
+    /// logged in, not in a room
+    Lobby,
+    InRoom,
+    Queued,
+    /// the server asked the room to ready up
+    ReadyCheck,
+    /// prestart_get sent, or held back by a misbehaving user, waiting for start_get
+    Ready,
+    Playing,
+}
+
+impl Default for UserState {
+    fn default() -> UserState {
+        UserState::Offline
+    }
+}
+
+/// The events that move a user between states.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transition {
+    Login,
+    Logout,
+    Create,
+    Join,
+    Close,
+    StartQueue,
+    Ready,
+    PreStart,
+    StopQueue,
+    CancelQueue,
+    Declined,
+    StartGet,
+    GameOver,
+    Afk,
+}
+
+impl UserState {
+    /// The state after `t`, or None when `t` is not valid in this state.
+    pub fn next(self, t: Transition) -> Option<UserState> {
+        use self::UserState::*;
+        match (self, t) {
+            (Offline, Transition::Login) => Some(Lobby),
+            (Offline, Transition::Logout) => None,
+            (_, Transition::Logout) => Some(Offline),
+            (_, Transition::Afk) => Some(Offline),
+            (Lobby, Transition::Create) => Some(InRoom),
+            (Lobby, Transition::Join) => Some(InRoom),
+            (InRoom, Transition::Close) | (Queued, Transition::Close) | (ReadyCheck, Transition::Close) => Some(Lobby),
+            (InRoom, Transition::StartQueue) => Some(Queued),
+            // party members only learn about the queue through the ready check
+            (InRoom, Transition::Ready) | (Queued, Transition::Ready) => Some(ReadyCheck),
+            (ReadyCheck, Transition::PreStart) => Some(Ready),
+            (ReadyCheck, Transition::StopQueue) | (Ready, Transition::StopQueue) => Some(Queued),
+            // a room that declined or let the ready check time out is not queued again
+            (ReadyCheck, Transition::Declined) => Some(InRoom),
+            (Queued, Transition::CancelQueue) | (ReadyCheck, Transition::CancelQueue) | (Ready, Transition::CancelQueue) => Some(InRoom),
+            (ReadyCheck, Transition::StartGet) | (Ready, Transition::StartGet) => Some(Playing),
+            (Playing, Transition::GameOver) => Some(Lobby),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct User {
@@ -13,131 +86,283 @@ pub struct User {
     pub hero: String,
     pub room: String,
     pub cnt: i32,
-    pub isLogin: bool,
-    pub isInRoom: bool,
+    pub state: UserState,
     pub isRoomCreater: bool,
     pub isChooseNGHero: bool,
-    pub isStartQueue: bool,
-    pub isCanPreStart: bool,
-    pub isPreStart: bool,
-    pub isPlaying: bool,
+    pub isShed: bool,
+    pub sent: HashMap<String, Instant>,
+    pub rng: SimRng,
+    pub profile: Profile,
+    pub ready_check: ReadyCheck,
+    /// answer to the ready check in progress
+    pub ready_answer: Option<ReadyAnswer>,
+    /// when the user accepted the ready check in progress, cleared once the server resolves it
+    pub accepted_at: Option<Instant>,
+    /// when and how the last ready check this user declined or let time out was cancelled,
+    /// for the penalty check
+    pub declined: Option<(Instant, ReadyAnswer)>,
+    /// when the room went into the queue, for time-to-match
+    pub queued_at: Option<Instant>,
+    /// when the ready check came in, for time-to-start
+    pub matched_at: Option<Instant>,
+    /// members in the room when it was matched
+    pub party: usize,
+}
+
+/// Where a room is in its life, as far as the server told us.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomState {
+    Open,
+    Queued,
+    InGame,
+    /// gone on the server, dropped from the registry on the next cleanup
+    Closed,
+}
+
+impl Default for RoomState {
+    fn default() -> RoomState {
+        RoomState::Open
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct RoomRecord {
     pub id: String,
     pub ids: Vec<String>,
+    pub state: RoomState,
 }
 
+/// members a bot's room takes, rooms are only joined on their own shard (see `engine::run_shard`)
 const TEAM_SIZE: usize = 1;
+/// the only queue mode the bots play
+pub const MODE: &str = "rk";
+/// requests the server answers on the user's own res topic
+const ANSWERED: &[&str] = &["login", "logout", "create", "join", "close", "start_queue", "cancel_queue", "choose_hero"];
 
 impl User {
+    /// Apply `t`, logging and counting it when the current state does not allow it.
+    pub fn transition(&mut self, t: Transition) -> bool {
+        match self.state.next(t) {
+            Some(s) => {
+                self.state = s;
+                true
+            }
+            None => {
+                stats::inc(&stats::INVALID_TRANSITIONS);
+                warn!("user {} invalid transition {:?} in {:?}", self.id, t, self.state);
+                false
+            }
+        }
+    }
+
     pub fn next_action(&mut self, tx: &mut Sender<MqttMsg>, rooms: &mut IndexMap<String, Rc<RefCell<RoomRecord>>>) {
-        let mut rng = rand::thread_rng();
-        let mut r = rng.gen_range(0, 10);
+        if self.isShed {
+            return ()
+        }
+        let mut r = self.rng.gen_range(0, 10);
         if r > 4 {
             return ()
         }
-        if self.cnt >= 0 && self.cnt < 150 || self.isPlaying{
+        if self.state == UserState::Playing && self.profile == Profile::AbandonGame {
+            self.misbehave();
+            self.afk();
+            return ()
+        }
+        if self.cnt >= 0 && self.cnt < 150 || self.state == UserState::Playing {
             self.cnt += 1;
             return()
         }
-        if !self.isLogin {
-            self.login(tx);
-        } else if !self.isInRoom {
-            r = rng.gen_range(0, 10);
-            if r < 5 {
-                self.create(tx);
-                let id = self.id.clone();
-                rooms.insert(
-                    id.clone(),
-                    Rc::new(RefCell::new(
-                        RoomRecord{id: id.clone(), ids:vec![id.clone()]}
-                    )));
-            } else if rooms.len() > 0 {
-                let mut n = rng.gen_range(0, rooms.len());
-                let (id, rr) = rooms.get_index(n).unwrap();
-                if rr.borrow().ids.len() < TEAM_SIZE {
-                    self.join(tx, &rr);
+        match self.state {
+            UserState::Offline => {
+                self.login(tx);
+            }
+            UserState::Lobby => {
+                r = self.rng.gen_range(0, 10);
+                if r < 5 {
+                    // the room is registered once the server created it
+                    self.create(tx);
+                } else if rooms.len() > 0 {
+                    let mut n = self.rng.gen_range(0, rooms.len());
+                    let (id, rr) = rooms.get_index(n).unwrap();
+                    if rr.borrow().state == RoomState::Open && rr.borrow().ids.len() < TEAM_SIZE {
+                        self.join(tx, &rr);
+                    }
+                }
+            }
+            UserState::InRoom => {
+                self.start_queue(tx);
+            }
+            UserState::ReadyCheck => {
+                if self.profile == Profile::AfkReadyCheck {
+                    self.misbehave();
+                    self.afk();
+                } else {
+                    self.answer_ready_check(tx);
                 }
             }
+            _ => {}
         }
-        else if !self.isStartQueue {
-            self.start_queue(tx);
+        self.cnt = 0;
+    }
+
+    /// Accept, decline or ignore the ready check, picked once per ready check.
+    pub fn answer_ready_check(&mut self, tx: &mut Sender<MqttMsg>) {
+        let first = self.ready_answer.is_none();
+        if first {
+            let a = self.ready_check.pick(&mut self.rng);
+            match a {
+                ReadyAnswer::Accept => stats::inc(&stats::READY_ACCEPTED),
+                ReadyAnswer::Decline => stats::inc(&stats::READY_DECLINED),
+                ReadyAnswer::Timeout => stats::inc(&stats::READY_TIMED_OUT),
+            }
+            self.ready_answer = Some(a);
         }
-        else if self.isCanPreStart {
-            self.ready(tx);
+        match self.ready_answer {
+            Some(ReadyAnswer::Accept) => {
+                if self.accepted_at.is_none() {
+                    self.accepted_at = Some(Instant::now());
+                }
+                self.ready(tx, true);
+            }
+            Some(ReadyAnswer::Decline) if first => self.ready(tx, false),
+            _ => {}
         }
-        self.cnt = 0;
     }
+
+    /// Flag an accepted ready check the server never resolved.
+    pub fn check_ready(&mut self) {
+        if let Some(t) = self.accepted_at {
+            if t.elapsed() > Duration::from_secs(self.ready_check.resolve) {
+                self.accepted_at = None;
+                self.ready_violation(format!("user {} accepted the ready check {}s ago and is still in {:?}",
+                    self.id, self.ready_check.resolve, self.state));
+            }
+        }
+    }
+
+    fn ready_violation(&self, e: String) {
+        warn!("{}", e);
+        stats::inc(&stats::READY_VIOLATIONS);
+        stats::record_error(e);
+    }
+
+    /// Log and count this user's profile kicking in.
+    fn misbehave(&self) {
+        info!("user {} misbehaves: {} in {:?}", self.id, self.profile.name(), self.state);
+        stats::record_misbehaviour(self.profile.name());
+    }
+    /// One step back towards Offline: cancel the queue, close the room, log out.
+    /// Called on every tick while shutting down, a step is only resent once its response is overdue.
     pub fn back_action(&mut self, tx: &mut Sender<MqttMsg>) {
-        if self.isLogin {
-            self.logout(tx);
+        match self.state {
+            // the game finishes by itself
+            UserState::Offline | UserState::Playing => {}
+            UserState::Queued | UserState::ReadyCheck | UserState::Ready if self.isRoomCreater => {
+                if !self.awaiting("cancel_queue") {
+                    self.cancel_queue(tx);
+                }
+            }
+            UserState::InRoom if self.isRoomCreater => {
+                if !self.awaiting("close") {
+                    self.close(tx);
+                }
+            }
+            _ => {
+                if !self.awaiting("logout") {
+                    self.logout(tx);
+                }
+            }
+        }
+    }
+
+    /// Sent `action` recently and still waiting for the response.
+    fn awaiting(&self, action: &str) -> bool {
+        self.sent.get(action).map(|t| t.elapsed() < Duration::from_secs(2)).unwrap_or(false)
+    }
+
+    /// Drop and return the requests that have gone unanswered for `deadline`.
+    pub fn expired(&mut self, deadline: Duration) -> Vec<String> {
+        let late: Vec<String> = self.sent.iter()
+            .filter(|(a, t)| ANSWERED.contains(&a.as_str()) && t.elapsed() >= deadline)
+            .map(|(a, _)| a.clone())
+            .collect();
+        for a in &late {
+            self.sent.remove(a);
         }
-        else if self.isInRoom && self.isRoomCreater {
-            self.close(tx);
+        late
+    }
+
+    fn send(&mut self, tx: &mut Sender<MqttMsg>, topic: String, msg: String) {
+        if let Some(action) = topic.rsplit('/').next() {
+            self.sent.insert(action.to_owned(), Instant::now());
         }
-        else if !self.isStartQueue {
-            self.start_queue(tx);
+        let m = MqttMsg{topic:topic, msg:msg, from:self.id.clone()};
+        if !send_msg(tx, m) && backpressure() == Backpressure::Shed && !self.isShed {
+            self.isShed = true;
+            stats::inc(&stats::USERS_SHED);
+            warn!("user {} shed, publish channel full", self.id);
         }
-        else if self.isCanPreStart {
-            self.ready(tx);
+    }
+
+    /// Record the response latency of the last `action` request this user sent.
+    pub fn got_res(&mut self, action: &str) {
+        if let Some(t) = self.sent.remove(action) {
+            stats::observe_latency(action, t.elapsed().as_secs_f64());
         }
     }
 
-    pub fn login(&self, tx: &mut Sender<MqttMsg>) {
-        if !self.isLogin {
+    pub fn login(&mut self, tx: &mut Sender<MqttMsg>) {
+        if self.state == UserState::Offline {
             let msg = format!(r#"{{"id":"{}"}}"#, self.id);
             let topic = format!("member/{}/send/login", self.id);
-            tx.try_send(MqttMsg{topic:topic, msg:msg});
+            self.send(tx, topic, msg);
         }
     }
 
-    pub fn join(&self, tx: &mut Sender<MqttMsg>, room: &Rc<RefCell<RoomRecord>>) {
-        if !self.isInRoom {
+    pub fn join(&mut self, tx: &mut Sender<MqttMsg>, room: &Rc<RefCell<RoomRecord>>) {
+        if self.state == UserState::Lobby {
             let msg = format!(r#"{{"room":"{}", "join":"{}"}}"#, room.borrow().id, self.id);
             let topic = format!("room/{}/send/join", self.id);
-            tx.try_send(MqttMsg{topic:topic, msg:msg});
+            self.send(tx, topic, msg);
         }
     }
     pub fn get_join(&mut self, room: String) {
-        self.isInRoom = true;
-        self.room = room;
+        if self.transition(Transition::Join) {
+            self.room = room;
+        }
     }
 
     pub fn get_login(&mut self) {
-        self.isLogin = true;
+        self.transition(Transition::Login);
     }
-    pub fn logout(&self, tx: &mut Sender<MqttMsg>) {
-        if self.isLogin {
+    pub fn logout(&mut self, tx: &mut Sender<MqttMsg>) {
+        if self.state != UserState::Offline {
             let msg = format!(r#"{{"id":"{}"}}"#, self.id);
             let topic = format!("member/{}/send/logout", self.id);
-            tx.try_send(MqttMsg{topic:topic, msg:msg});
+            self.send(tx, topic, msg);
         }
     }
     pub fn game_over(&mut self) {
-        self.isStartQueue = false;
-        self.isPreStart = false;
-        self.isPlaying = false;
-        self.isInRoom = false;
-        self.isRoomCreater = false;
-        self.isCanPreStart = false;
+        if self.transition(Transition::GameOver) {
+            self.isChooseNGHero = false;
+            self.isRoomCreater = false;
+            self.room = "".to_owned();
+        }
     }
     pub fn get_logout(&mut self) {
-        self.isLogin = false;
-        self.isChooseNGHero = false;
-        self.isStartQueue = false;
-        self.isPreStart = false;
-        self.isPlaying = false;
-        self.isInRoom = false;
-        self.isRoomCreater = false;
+        self.clear_queue_times();
+        if self.transition(Transition::Logout) {
+            self.isChooseNGHero = false;
+            self.isRoomCreater = false;
+            self.room = "".to_owned();
+        }
     }
     pub fn choose_hero(&mut self, tx: &mut Sender<MqttMsg>, hero: String) {
         self.hero = hero;
         if !self.isChooseNGHero {
             let msg = format!(r#"{{"id":"{}", "hero":"{}"}}"#, self.id, self.hero);
             let topic = format!("member/{}/send/choose_hero", self.id);
-            tx.try_send(MqttMsg{topic:topic, msg:msg});
+            self.send(tx, topic, msg);
         }
     }
     pub fn get_choose_hero(&mut self, hero: String) {
@@ -145,86 +370,449 @@ impl User {
         self.isChooseNGHero = true;
     }
     pub fn choose_random_hero(&mut self, tx: &mut Sender<MqttMsg>) {
+        let hero = TEST_HEROES.choose(&mut *self.rng).unwrap().to_string();
+        self.choose_hero(tx, hero);
     }
     pub fn create(&mut self, tx: &mut Sender<MqttMsg>) {
-        if !self.isInRoom {
+        if self.state == UserState::Lobby {
             let msg = format!(r#"{{"id":"{}","mode":"ng"}}"#, self.id);
             let topic = format!("room/{}/send/create", self.id);
-            tx.try_send(MqttMsg{topic:topic, msg:msg});
+            self.send(tx, topic, msg);
             self.room = self.id.clone();
         }
     }
     pub fn get_create(&mut self) {
-        self.isInRoom = true;
-        self.room = self.id.clone();
-        self.isRoomCreater = true;
+        if self.transition(Transition::Create) {
+            self.room = self.id.clone();
+            self.isRoomCreater = true;
+        }
     }
-    pub fn close(&self, tx: &mut Sender<MqttMsg>) {
-        if self.isInRoom {
+    pub fn close(&mut self, tx: &mut Sender<MqttMsg>) {
+        if self.state == UserState::InRoom || self.state == UserState::Queued {
             let msg = format!(r#"{{"id":"{}"}}"#, self.id);
             let topic = format!("room/{}/send/close", self.id);
-            tx.try_send(MqttMsg{topic:topic, msg:msg});
+            self.send(tx, topic, msg);
         }
     }
     pub fn get_close(&mut self) {
-        self.isInRoom = false;
-        self.room = "".to_owned();
+        self.clear_queue_times();
+        if self.transition(Transition::Close) {
+            self.isRoomCreater = false;
+            self.room = "".to_owned();
+        }
     }
     pub fn start_queue(&mut self, tx: &mut Sender<MqttMsg>) {
-        if self.isInRoom && !self.isRoomCreater {
-            self.isStartQueue = true;
+        if self.state != UserState::InRoom {
+            return;
         }
-        if !self.isStartQueue {
-            let msg = format!(r#"{{"id":"{}", "action":"start queue", "room":"{}", "mode":"rk"}}"#, self.id, self.room);
+        if !self.isRoomCreater {
+            // only the creater queues the room, members just follow
+            if self.transition(Transition::StartQueue) {
+                self.queued_at = Some(Instant::now());
+            }
+        } else {
+            let msg = format!(r#"{{"id":"{}", "action":"start queue", "room":"{}", "mode":"{}"}}"#, self.id, self.room, MODE);
             let topic = format!("room/{}/send/start_queue", self.room);
-            tx.try_send(MqttMsg{topic:topic, msg:msg});
+            self.send(tx, topic, msg);
+        }
+    }
+    pub fn get_start_queue(&mut self, msg: &str) {
+        let penalty = Duration::from_secs(self.ready_check.penalty);
+        let penalised = self.declined.map(|(t, _)| t.elapsed() < penalty).unwrap_or(false);
+        if msg != "ok" {
+            // back off before trying again
+            self.cnt = 0;
+            if penalised {
+                stats::inc(&stats::READY_PENALISED);
+            } else {
+                warn!("user {} start_queue refused: {}", self.id, msg);
+                stats::record_error(format!("user {} start_queue refused: {}", self.id, msg));
+            }
+            return;
+        }
+        // a timed out user can't tell whether it caused the cancel, only decliners are checked
+        if penalised && self.declined.map(|(_, a)| a == ReadyAnswer::Decline).unwrap_or(false) {
+            self.ready_violation(format!("user {} queued again during the decline penalty", self.id));
+        }
+        self.declined = None;
+        if self.transition(Transition::StartQueue) {
+            self.queued_at = Some(Instant::now());
+        }
+    }
+
+    pub fn cancel_queue(&mut self, tx: &mut Sender<MqttMsg>) {
+        if self.isRoomCreater {
+            let msg = format!(r#"{{"id":"{}", "room":"{}"}}"#, self.id, self.room);
+            let topic = format!("room/{}/send/cancel_queue", self.room);
+            self.send(tx, topic, msg);
         }
     }
-    pub fn get_start_queue(&mut self) {
-        self.isStartQueue = true;
+    pub fn get_cancel_queue(&mut self) {
+        self.transition(Transition::CancelQueue);
+        self.clear_queue_times();
     }
 
     pub fn start_get (&mut self) {
-        self.isPreStart = true;
+        self.transition(Transition::StartGet);
     }
 
-    pub fn ready(&mut self, tx: &mut Sender<MqttMsg>) {
-        if self.isCanPreStart && !self.isPreStart {
-            //self.isPreStart = true;
-            let mut rng = rand::thread_rng();
-            let msg = format!(r#"{{"room":"{}", "id":"{}", "accept":true}}"#, self.room, self.id);
+    pub fn ready(&mut self, tx: &mut Sender<MqttMsg>, accept: bool) {
+        if self.state == UserState::ReadyCheck {
+            let msg = format!(r#"{{"room":"{}", "id":"{}", "accept":{}}}"#, self.room, self.id, accept);
             let topic = format!("room/{}/send/ready", self.id);
-            tx.try_send(MqttMsg{topic:topic, msg:msg}).unwrap();
+            self.send(tx, topic, msg);
+        }
+    }
+    /// `party` is how many members the matched room has.
+    pub fn get_ready(&mut self, party: usize) {
+        if self.transition(Transition::Ready) {
+            // members that never saw their room queue have nothing to measure from
+            if let Some(t) = self.queued_at.take() {
+                stats::observe_queue("match", party, MODE, t.elapsed().as_secs_f64());
+            }
+            self.matched_at = Some(Instant::now());
+            self.party = party;
+        }
+    }
+    /// The game this user was matched into started.
+    pub fn game_started(&mut self) {
+        if let Some(t) = self.matched_at.take() {
+            stats::observe_queue("start", self.party, MODE, t.elapsed().as_secs_f64());
         }
     }
-    pub fn get_ready(&mut self) {
-        self.isCanPreStart = true;
+    fn clear_queue_times(&mut self) {
+        self.queued_at = None;
+        self.matched_at = None;
     }
     pub fn get_prestart(&mut self, res: bool, tx: &mut Sender<MqttMsg>) {
-        //
+        let answer = self.ready_answer.take();
+        self.accepted_at = None;
         if res == false {
-            self.isPreStart = false;
+            match answer {
+                Some(ReadyAnswer::Decline) | Some(ReadyAnswer::Timeout) => {
+                    if self.transition(Transition::Declined) {
+                        self.clear_queue_times();
+                        self.declined = answer.map(|a| (Instant::now(), a));
+                        // try to queue again right away, the server should refuse during the penalty
+                        self.cnt = -1;
+                    }
+                }
+                _ => {
+                    if self.transition(Transition::StopQueue) {
+                        // back in the queue, the wait starts over
+                        self.queued_at = Some(Instant::now());
+                        self.matched_at = None;
+                        if answer == Some(ReadyAnswer::Accept) {
+                            stats::inc(&stats::READY_REQUEUED);
+                        }
+                    }
+                }
+            }
         }
         if res == true {
-            let mut rng = rand::thread_rng();
-            let mut r = rng.gen_range(0, 10);
-            if r < 8 {
-                self.isCanPreStart = res;
-                tx.try_send(MqttMsg{topic: format!(r#"room/{}/send/prestart_get"#, self.id), msg: format!(r#"{{"room":"{}", "id":"{}"}}"#, self.room, self.id)});
+            if answer == Some(ReadyAnswer::Decline) || answer == Some(ReadyAnswer::Timeout) {
+                self.ready_violation(format!("user {} game started without accepting the ready check", self.id));
+            }
+            if self.profile == Profile::NoPrestartGet {
+                self.misbehave();
+                // past the ready check all the same, or it would be answered again
+                self.transition(Transition::PreStart);
+                return;
+            }
+            self.accept_prestart(tx);
+            // hero pick comes with the accepted game
+            if self.profile == Profile::DropHeroPick {
+                self.misbehave();
+                self.afk();
+            } else {
+                self.choose_random_hero(tx);
             }
         }
     }
+    pub fn accept_prestart(&mut self, tx: &mut Sender<MqttMsg>) {
+        if self.transition(Transition::PreStart) {
+            let topic = format!(r#"room/{}/send/prestart_get"#, self.id);
+            let msg = format!(r#"{{"room":"{}", "id":"{}"}}"#, self.room, self.id);
+            self.send(tx, topic, msg);
+        }
+    }
     pub fn invite(&mut self, tx: &mut Sender<MqttMsg>) {
-        
+
     }
     pub fn get_invite(&mut self) {
-        
+
     }
     pub fn afk(&mut self) {
-        self.isLogin = false;
+        self.transition(Transition::Afk);
+        self.clear_queue_times();
         self.isChooseNGHero = false;
-        self.isStartQueue = false;
-        self.isPreStart = false;
-        self.isPlaying = false;
+        self.isRoomCreater = false;
+        self.room = "".to_owned();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str) -> User {
+        User { id: id.to_owned(), cnt: -1, ..Default::default() }
+    }
+
+    fn topics(rx: &Receiver<MqttMsg>) -> Vec<String> {
+        rx.try_iter().map(|m| m.topic).collect()
+    }
+
+    #[test]
+    fn full_cycle_as_room_creater() {
+        let (mut tx, rx) = bounded(100);
+        let mut u = user("7");
+
+        u.login(&mut tx);
+        u.get_login();
+        assert_eq!(u.state, UserState::Lobby);
+
+        u.create(&mut tx);
+        u.get_create();
+        assert_eq!(u.state, UserState::InRoom);
+        assert!(u.isRoomCreater);
+        assert_eq!(u.room, "7");
+
+        u.start_queue(&mut tx);
+        u.get_start_queue("ok");
+        assert_eq!(u.state, UserState::Queued);
+
+        u.get_ready(1);
+        assert_eq!(u.state, UserState::ReadyCheck);
+        u.ready(&mut tx, true);
+
+        u.accept_prestart(&mut tx);
+        assert_eq!(u.state, UserState::Ready);
+
+        u.start_get();
+        assert_eq!(u.state, UserState::Playing);
+
+        u.game_over();
+        assert_eq!(u.state, UserState::Lobby);
+        assert!(!u.isRoomCreater);
+
+        assert_eq!(topics(&rx), vec![
+            "member/7/send/login",
+            "room/7/send/create",
+            "room/7/send/start_queue",
+            "room/7/send/ready",
+            "room/7/send/prestart_get",
+        ]);
+    }
+
+    #[test]
+    fn member_follows_room_queue() {
+        let (mut tx, rx) = bounded(100);
+        let mut u = user("8");
+        u.get_login();
+        u.get_join("7".to_owned());
+        assert_eq!(u.state, UserState::InRoom);
+        assert!(!u.isRoomCreater);
+
+        u.start_queue(&mut tx);
+        assert_eq!(u.state, UserState::Queued);
+        assert!(topics(&rx).is_empty());
+    }
+
+    #[test]
+    fn messages_carry_sender() {
+        let (mut tx, rx) = bounded(100);
+        let mut u = user("7");
+        u.login(&mut tx);
+        u.get_login();
+        u.create(&mut tx);
+        let sent: Vec<String> = rx.try_iter().map(|m| m.from).collect();
+        assert_eq!(sent, vec!["7".to_owned(), "7".to_owned()]);
+    }
+
+    #[test]
+    fn expired_gives_up_unanswered_requests() {
+        let (mut tx, _rx) = bounded(100);
+        let mut u = user("7");
+        u.login(&mut tx);
+        assert!(u.expired(Duration::from_secs(10)).is_empty());
+        assert_eq!(u.expired(Duration::from_secs(0)), vec!["login".to_owned()]);
+        assert!(u.sent.is_empty());
+    }
+
+    #[test]
+    fn stop_queue_returns_to_queue() {
+        let (mut tx, _rx) = bounded(100);
+        let mut u = user("7");
+        u.get_login();
+        u.get_create();
+        u.get_start_queue("ok");
+        u.get_ready(1);
+        u.accept_prestart(&mut tx);
+        u.get_prestart(false, &mut tx);
+        assert_eq!(u.state, UserState::Queued);
+    }
+
+    #[test]
+    fn invalid_transitions_keep_state() {
+        let mut u = user("7");
+        u.get_ready(1);
+        assert_eq!(u.state, UserState::Offline);
+        u.get_login();
+        u.start_get();
+        assert_eq!(u.state, UserState::Lobby);
+        u.game_over();
+        assert_eq!(u.state, UserState::Lobby);
+        u.get_login();
+        assert_eq!(u.state, UserState::Lobby);
+    }
+
+    #[test]
+    fn same_seed_same_decisions() {
+        let run = || {
+            let (mut tx, rx) = bounded(1000);
+            let mut rooms = IndexMap::new();
+            let mut u = User { rng: crate::rng::user_rng(42, "7"), ..user("7") };
+            for _ in 0..2000 {
+                u.next_action(&mut tx, &mut rooms);
+                match u.state {
+                    UserState::Offline => u.get_login(),
+                    UserState::Lobby if !u.room.is_empty() => u.get_create(),
+                    _ => {}
+                }
+            }
+            topics(&rx)
+        };
+        let a = run();
+        assert!(a.len() > 1);
+        assert_eq!(a, run());
+    }
+
+    #[test]
+    fn afk_and_logout_leave_room() {
+        let mut u = user("7");
+        u.get_login();
+        u.get_create();
+        u.get_start_queue("ok");
+        u.afk();
+        assert_eq!(u.state, UserState::Offline);
+        assert_eq!(u.room, "");
+        assert!(!u.isRoomCreater);
+
+        u.get_login();
+        u.get_join("9".to_owned());
+        u.get_logout();
+        assert_eq!(u.state, UserState::Offline);
+        assert_eq!(u.room, "");
+    }
+
+    #[test]
+    fn back_action_unwinds_to_offline() {
+        let (mut tx, rx) = bounded(100);
+        let mut u = user("7");
+        u.get_login();
+        u.get_create();
+        u.get_start_queue("ok");
+        u.get_ready(1);
+
+        u.back_action(&mut tx);
+        // waiting for the response, nothing resent
+        u.back_action(&mut tx);
+        u.get_cancel_queue();
+        assert_eq!(u.state, UserState::InRoom);
+
+        u.back_action(&mut tx);
+        u.get_close();
+        assert_eq!(u.state, UserState::Lobby);
+
+        u.back_action(&mut tx);
+        u.get_logout();
+        assert_eq!(u.state, UserState::Offline);
+
+        u.back_action(&mut tx);
+        assert_eq!(topics(&rx), vec![
+            "room/7/send/cancel_queue",
+            "room/7/send/close",
+            "member/7/send/logout",
+        ]);
+    }
+
+    #[test]
+    fn queue_times_follow_the_match() {
+        let (mut tx, _rx) = bounded(100);
+        let mut u = user("7");
+        u.get_login();
+        u.get_create();
+        u.get_start_queue("ok");
+        assert!(u.queued_at.is_some());
+
+        // party of 4 is not used by any other test, the stats are shared
+        u.get_ready(4);
+        assert!(u.queued_at.is_none());
+        assert!(u.matched_at.is_some());
+        assert_eq!(u.party, 4);
+        assert!(stats::queue_times().iter().any(|q| q.phase == "match" && q.party == 4 && q.mode == MODE));
+
+        // requeued, waiting again from now
+        u.ready_answer = Some(ReadyAnswer::Accept);
+        u.get_prestart(false, &mut tx);
+        assert!(u.queued_at.is_some());
+        assert!(u.matched_at.is_none());
+
+        u.get_ready(4);
+        u.accept_prestart(&mut tx);
+        u.start_get();
+        u.game_started();
+        assert!(u.matched_at.is_none());
+        assert!(stats::queue_times().iter().any(|q| q.phase == "start" && q.party == 4));
+    }
+
+    #[test]
+    fn no_prestart_get_never_answers() {
+        let (mut tx, rx) = bounded(100);
+        let mut rooms = IndexMap::new();
+        let mut u = User { rng: crate::rng::user_rng(1, "7"), ..user("7") };
+        u.profile = Profile::NoPrestartGet;
+        u.ready_check = ReadyCheck { accept: 100, decline: 0, timeout: 0, penalty: 0, resolve: 0 };
+        u.get_login();
+        u.get_create();
+        u.get_start_queue("ok");
+        u.get_ready(1);
+        u.answer_ready_check(&mut tx);
+        let violations = stats::get(&stats::READY_VIOLATIONS);
+        u.get_prestart(true, &mut tx);
+        for _ in 0..2 {
+            // ticked like the shard does right after a response
+            u.cnt = -1;
+            u.check_ready();
+            u.next_action(&mut tx, &mut rooms);
+        }
+        assert_eq!(u.state, UserState::Ready);
+        assert_eq!(topics(&rx), vec!["room/7/send/ready"]);
+        assert_eq!(stats::get(&stats::READY_VIOLATIONS), violations);
+    }
+
+    #[test]
+    fn decliner_goes_back_to_room_and_is_penalised() {
+        let (mut tx, rx) = bounded(100);
+        let mut u = user("7");
+        u.ready_check = ReadyCheck { accept: 0, decline: 100, timeout: 0, penalty: 60, resolve: 30 };
+        u.get_login();
+        u.get_create();
+        u.get_start_queue("ok");
+        u.get_ready(1);
+        u.answer_ready_check(&mut tx);
+        // declined once only
+        u.answer_ready_check(&mut tx);
+        assert_eq!(rx.try_iter().map(|m| m.msg).collect::<Vec<_>>(),
+            vec![r#"{"room":"7", "id":"7", "accept":false}"#]);
+
+        u.get_prestart(false, &mut tx);
+        assert_eq!(u.state, UserState::InRoom);
+
+        let violations = stats::get(&stats::READY_VIOLATIONS);
+        u.get_start_queue("penalty");
+        assert_eq!(u.state, UserState::InRoom);
+        assert_eq!(stats::get(&stats::READY_VIOLATIONS), violations);
     }
 }
@@ -0,0 +1,172 @@
+use log::{info, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crossbeam_channel::{bounded, tick, Sender, Receiver, select};
+use failure::Error;
+use mysql;
+
+use crate::db;
+use crate::event::{GameOverData, GameInfoData};
+use crate::msg::{send_msg, MqttMsg};
+use crate::stats;
+
+/// A game whose results we published and expect the server to persist.
+#[derive(Clone, Debug)]
+pub struct VerifyJob {
+    pub over: GameOverData,
+    pub info: GameInfoData,
+    /// the players' ratings before game_over was published, for the elo check
+    pub before: BTreeMap<String, Option<i32>>,
+}
+
+struct Pending {
+    job: VerifyJob,
+    at: Instant,
+}
+
+/// A finished game on its way to the verifier thread, with the messages that tell the server.
+struct Submitted {
+    over: GameOverData,
+    info: GameInfoData,
+    results: Vec<MqttMsg>,
+    publish: Sender<MqttMsg>,
+}
+
+/// Where finished games are handed to the verifier thread.
+#[derive(Clone, Debug)]
+pub struct Verifier {
+    tx: Sender<Submitted>,
+}
+
+impl Verifier {
+    /// Queue a game for checking instead of publishing its `results`. The verifier thread reads
+    /// the players' ratings first and then publishes them on `publish`, so the server can't have
+    /// applied the result yet.
+    pub fn submit(&self, over: GameOverData, info: GameInfoData, results: Vec<MqttMsg>, publish: &Sender<MqttMsg>) {
+        let s = Submitted { over: over, info: info, results: results, publish: publish.clone() };
+        if let Err(e) = self.tx.try_send(s) {
+            let s = e.into_inner();
+            warn!("verifier busy, game {} is not checked", s.over.game);
+            stats::record_error(format!("game {} not verified, verifier busy", s.over.game));
+            for m in s.results {
+                send_msg(&s.publish, m);
+            }
+        }
+    }
+}
+
+/// Start the verifier thread. Every job is checked against the database `delay` after it was queued.
+/// The thread ends once every `Verifier` is dropped and the queued games are checked, join it
+/// before reporting so the last games are in.
+pub fn start(url: &str, delay: Duration) -> Result<(Verifier, JoinHandle<()>), Error> {
+    let pool = db::connect(url)?;
+    let (tx, rx): (Sender<Submitted>, Receiver<Submitted>) = bounded(10000);
+    let handle = thread::spawn(move || {
+        let mut pending: VecDeque<Pending> = VecDeque::new();
+        let update = tick(Duration::from_millis(1000));
+        let mut open = true;
+        while open || !pending.is_empty() {
+            let rx = if open { rx.clone() } else { crossbeam_channel::never() };
+            select! {
+                recv(rx) -> job => {
+                    match job {
+                        Ok(s) => {
+                            let mut before = BTreeMap::new();
+                            for id in s.over.win.iter().chain(s.over.lose.iter()) {
+                                before.insert(id.clone(), db::rating(&pool, id).unwrap_or(None));
+                            }
+                            for m in s.results {
+                                send_msg(&s.publish, m);
+                            }
+                            let job = VerifyJob { over: s.over, info: s.info, before: before };
+                            pending.push_back(Pending { job: job, at: Instant::now() + delay });
+                        }
+                        // nobody can queue games anymore
+                        Err(_) => open = false,
+                    }
+                }
+                recv(update) -> _ => {
+                    while pending.front().map(|p| p.at <= Instant::now()).unwrap_or(false) {
+                        let p = pending.pop_front().unwrap();
+                        let game = p.job.over.game;
+                        match check(&pool, &p) {
+                            Ok(m) => {
+                                if m.is_empty() {
+                                    stats::inc(&stats::GAMES_VERIFIED);
+                                } else {
+                                    warn!("game {} persisted wrong: {:?}", game, m);
+                                    stats::inc(&stats::GAMES_MISMATCHED);
+                                    stats::record_error(format!("game {} persisted wrong", game));
+                                    stats::record_mismatch(game, m);
+                                }
+                            }
+                            Err(e) => {
+                                warn!("verify game {} failed: {}", game, e);
+                                stats::record_error(format!("verify game {} failed: {}", game, e));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    });
+    info!("verifying games {}s after game_over", delay.as_secs());
+    Ok((Verifier { tx: tx }, handle))
+}
+
+fn check(pool: &mysql::Pool, p: &Pending) -> Result<Vec<String>, Error> {
+    let game = p.job.over.game;
+    let mut m = Vec::new();
+
+    let mut res: BTreeMap<String, bool> = BTreeMap::new();
+    for row in pool.prep_exec(db::SQL_RESULT, (game,))? {
+        let (id, win) = mysql::from_row::<(String, bool)>(row?);
+        res.insert(id, win);
+    }
+    for (ids, win) in &[(&p.job.over.win, true), (&p.job.over.lose, false)] {
+        for id in ids.iter() {
+            match res.get(id) {
+                None => m.push(format!("{}: no result row", id)),
+                Some(w) if w != win => m.push(format!("{}: win is {} expected {}", id, w, win)),
+                _ => {}
+            }
+        }
+    }
+    if res.len() != p.job.over.win.len() + p.job.over.lose.len() {
+        m.push(format!("{} result rows for {} players", res.len(), p.job.over.win.len() + p.job.over.lose.len()));
+    }
+
+    let mut info = BTreeMap::new();
+    for row in pool.prep_exec(db::SQL_INFO, (game,))? {
+        let r = mysql::from_row::<(String, String, u16, u16, u16, u16, u16, u16, u16)>(row?);
+        info.insert(r.0.clone(), r);
+    }
+    for u in &p.job.info.users {
+        match info.get(&u.id) {
+            None => m.push(format!("{}: no game_info row", u.id)),
+            Some(r) => {
+                let want = (u.id.clone(), u.hero.clone(), u.level, u.damage, u.take_damage, u.heal, u.kill, u.death, u.assist);
+                if *r != want {
+                    m.push(format!("{}: game_info {:?} expected {:?}", u.id, r, want));
+                }
+            }
+        }
+    }
+
+    for (ids, win) in &[(&p.job.over.win, true), (&p.job.over.lose, false)] {
+        for id in ids.iter() {
+            let before = p.job.before.get(id).cloned().unwrap_or(None);
+            let after = db::rating(pool, id)?;
+            if let (Some(b), Some(a)) = (before, after) {
+                if *win && a <= b {
+                    m.push(format!("{}: won but rating {} -> {}", id, b, a));
+                }
+                if !*win && a >= b {
+                    m.push(format!("{}: lost but rating {} -> {}", id, b, a));
+                }
+            }
+        }
+    }
+    Ok(m)
+}
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use erps_test::transport::ChannelTransport;
+use erps_test::{mock, Config, Simulator, Transport};
+
+#[test]
+fn bots_play_against_the_mock_server_in_process() {
+    let (transport, server) = ChannelTransport::pair();
+    let server = thread::spawn(move || {
+        mock::serve(Arc::new(server), 1, Duration::from_secs(10), Duration::from_secs(0))
+    });
+
+    let cfg = Config { users: 20, seed: 1, ..Default::default() };
+    let sim = Simulator::start_with(Arc::new(transport), cfg).unwrap();
+    thread::sleep(Duration::from_secs(15));
+    let r = sim.shutdown(Duration::from_secs(5));
+
+    assert_eq!(r.latency["login"].count, 20);
+    assert_eq!(r.latency["logout"].count, 20);
+    assert!(r.ready_accepted > 0);
+    assert_eq!(r.parse_errors + r.topic_errors + r.handler_errors, 0);
+    assert_eq!(r.games_missing_start_get + r.users_gameless, 0);
+    // the bots hung up, so the server stops
+    assert!(server.join().is_ok());
+}
+
+#[test]
+fn only_one_subscriber_takes_the_responses() {
+    let (transport, _server) = ChannelTransport::pair();
+    assert!(transport.subscribe(0, &["member/+/res/+"]).is_ok());
+    assert!(transport.subscribe(1, &["member/+/res/+"]).is_err());
+}
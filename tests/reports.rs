@@ -0,0 +1,41 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+use erps_test::transport::ChannelTransport;
+use erps_test::{mock, Config, Report, Simulator};
+
+lazy_static! {
+    // the stats are process wide, simulators in this file take turns
+    static ref SERIAL: Mutex<()> = Mutex::new(());
+}
+
+fn run(users: usize, secs: u64) -> Report {
+    let _turn = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let (transport, server) = ChannelTransport::pair();
+    let server = thread::spawn(move || {
+        mock::serve(Arc::new(server), 1, Duration::from_secs(10), Duration::from_secs(0))
+    });
+    let sim = Simulator::start_with(Arc::new(transport), Config { users, seed: 2, ..Default::default() }).unwrap();
+    thread::sleep(Duration::from_secs(secs));
+    let r = sim.shutdown(Duration::from_secs(5));
+    let _ = server.join();
+    r
+}
+
+#[test]
+fn each_run_reports_only_its_own_users() {
+    let r = run(12, 4);
+    assert_eq!(r.latency["login"].count, 12);
+    assert_eq!(r.handler_errors, 0);
+}
+
+#[test]
+fn a_later_run_starts_from_zero() {
+    let r = run(5, 4);
+    assert_eq!(r.latency["login"].count, 5);
+    assert_eq!(r.latency["logout"].count, 5);
+    assert_eq!(r.invalid_transitions, 0);
+}